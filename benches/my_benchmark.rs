@@ -1,10 +1,16 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
 use bulletproofs_amcl::{
     r1cs::gadgets::{
         helper_constraints::poseidon::{SboxType},
-        merkle_tree_hash::PoseidonHash8
+        merkle_tree_hash::{Arity8MerkleTreeHash, PoseidonHash8}
     }
 };
+use merklespike::{El, Tree};
+use merklespike::bitmap::Bitmap;
+use merklespike::proof::AuthPath;
+use rand::Rng;
+
+const DEPTHS: [usize; 4] = [3, 6, 9, 12];
 
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("create Db", |b| b.iter(|| merklespike::make_db()));
@@ -18,11 +24,108 @@ fn criterion_benchmark(c: &mut Criterion) {
         params: &hash_params,
         sbox: &SboxType::Quint,
     };
-    c.bench_function("make tree depth 3", |b| b.iter(|| merklespike::make_tree(&hash_func, 3, &mut db)));
-    //c.bench_function("make tree depth 6", |b| b.iter(|| merklespike::make_tree(&hash_func, 6, &mut db)));
-    //c.bench_function("make tree depth 9", |b| b.iter(|| merklespike::make_tree(&hash_func, 9, &mut db)));
-    //c.bench_function("make tree depth 12", |b| b.iter(|| merklespike::make_tree(&hash_func, 12, &mut db)));
+
+    let mut group = c.benchmark_group("make tree");
+    for depth in DEPTHS.iter() {
+        group.bench_with_input(BenchmarkId::new("depth", depth), depth,
+            |b, &depth| b.iter(|| merklespike::make_tree(&hash_func, depth, &mut db)));
+    }
+    group.finish();
+
+    let inputs: Vec<El> = (0..8u64).map(El::from).collect();
+    c.bench_function("poseidon8 hash", |b| b.iter(|| hash_func.hash(inputs.clone()).unwrap()));
+}
+
+fn update_benchmark(c: &mut Criterion) {
+    let hash_params = merklespike::make_hash_params();
+    let hash_func = merklespike::make_hash_func(&hash_params);
+    let mut rng = rand::thread_rng();
+
+    let mut group = c.benchmark_group("update one leaf");
+    for depth in DEPTHS.iter() {
+        let mut db = merklespike::make_db();
+        let mut tree = Tree::new(&hash_func, *depth, &mut db).unwrap();
+        let capacity = 8u64.pow(*depth as u32);
+        group.bench_with_input(BenchmarkId::new("depth", depth), depth, |b, _| {
+            b.iter(|| {
+                let idx = El::from(rng.gen_range(0, capacity));
+                tree.update(&idx, El::one(), &mut db).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A bitmap with roughly `fill_ratio` of its bits set, at random.
+fn random_bitmap(bit_count: usize, fill_ratio: f64) -> Bitmap {
+    let mut rng = rand::thread_rng();
+    let mut b = Bitmap::new(bit_count).unwrap();
+    for _ in 0..(bit_count as f64 * fill_ratio) as usize {
+        b.set_bit(rng.gen_range(0, bit_count));
+    }
+    b
+}
+
+fn build_benchmark(c: &mut Criterion) {
+    let hash_params = merklespike::make_hash_params();
+    let hash_func = merklespike::make_hash_func(&hash_params);
+    let depth = 6;
+
+    let mut group = c.benchmark_group("build tree from bitmap (depth 6)");
+    group.sample_size(10);
+    for fill_ratio in [0.001, 0.01, 0.1].iter() {
+        let b = random_bitmap(8usize.pow(depth as u32), *fill_ratio);
+        group.bench_with_input(BenchmarkId::new("fill", fill_ratio), &b, |bench, b| {
+            bench.iter_batched(merklespike::make_db, |mut db| {
+                merklespike::build_tree_from_bitmap(depth, b, &hash_func, &mut db).unwrap().root
+            }, BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+fn snapshot_benchmark(c: &mut Criterion) {
+    let hash_params = merklespike::make_hash_params();
+    let hash_func = merklespike::make_hash_func(&hash_params);
+    let depth = 6;
+    let mut db = merklespike::make_db();
+    let b = random_bitmap(8usize.pow(depth as u32), 0.01);
+    let tree = merklespike::build_tree_from_bitmap(depth, &b, &hash_func, &mut db).unwrap();
+    let path = std::env::temp_dir().join("merklespike-bench.zip");
+
+    let mut group = c.benchmark_group("snapshot (depth 6, 1% full)");
+    group.sample_size(10);
+    group.bench_function("save", |bench| bench.iter(|| db.save(&path, &tree.root).unwrap()));
+    group.bench_function("load", |bench| {
+        bench.iter_batched(merklespike::make_db, |mut db2| db2.load(&path).unwrap(), BatchSize::LargeInput)
+    });
+    group.finish();
+    std::fs::remove_file(&path).ok();
+}
+
+fn proof_benchmark(c: &mut Criterion) {
+    let hash_params = merklespike::make_hash_params();
+    let hash_func = merklespike::make_hash_func(&hash_params);
+    let mut rng = rand::thread_rng();
+
+    let mut group = c.benchmark_group("auth path");
+    for depth in DEPTHS.iter() {
+        let mut db = merklespike::make_db();
+        let capacity = 8u64.pow(*depth as u32);
+        let mut tree = Tree::new(&hash_func, *depth, &mut db).unwrap();
+        for _ in 0..100 {
+            tree.update(&El::from(rng.gen_range(0, capacity)), El::one(), &mut db).unwrap();
+        }
+        let idx = rng.gen_range(0, capacity);
+        group.bench_with_input(BenchmarkId::new("generate", depth), depth,
+            |b, _| b.iter(|| AuthPath::new(&tree, &db, idx).unwrap()));
+        let auth_path = AuthPath::new(&tree, &db, idx).unwrap();
+        group.bench_with_input(BenchmarkId::new("verify", depth), depth,
+            |b, _| b.iter(|| auth_path.verify(&hash_func, &tree.root).unwrap()));
+    }
+    group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_group!(benches, criterion_benchmark, update_benchmark, build_benchmark,
+                 snapshot_benchmark, proof_benchmark);
+criterion_main!(benches);
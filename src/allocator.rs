@@ -0,0 +1,339 @@
+use std::fs;
+use std::path::Path;
+
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+
+use crate::MerkleError;
+use crate::bitmap::Bitmap;
+
+/// How an allocator picks the index for the next credential.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Strategy {
+    /// 0, 1, 2... Keeps the tree compact, but an index reveals roughly when
+    /// a credential was issued.
+    Sequential,
+    /// Uniformly random among the indices not yet issued. Best for privacy;
+    /// spreads set leaves across the whole tree.
+    Random,
+    /// Blocks of consecutive indices, handed out in shuffled order within
+    /// each block. A compromise: holders issued around the same time share
+    /// upper levels of the tree, but their order within a block is hidden.
+    ShuffledBlock { block_size: usize }
+}
+
+/// The most credentials one allocator tracks: 8^10, a tree of depth 10.
+/// Its two bitmaps then take 256 MiB, and loading saved state can't be
+/// made to allocate more.
+pub const MAX_CAPACITY: usize = 1 << 30;
+
+/// Hands out leaf indices for new credentials and tracks which indices are
+/// issued and which of those are revoked. Save its state after each
+/// allocation (and before the credential leaves the issuer); a restarted
+/// issuer that loads it will never hand out the same index twice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexAllocator {
+    strategy: Strategy,
+    issued: Bitmap,
+    revoked: Bitmap,
+    issued_count: usize,
+    /// Sequential: the next index to try. ShuffledBlock: the start of the
+    /// next block.
+    cursor: usize,
+    /// ShuffledBlock: what's left of the current block, last out first.
+    pending: Vec<usize>
+}
+
+impl IndexAllocator {
+    pub fn new(capacity: usize, strategy: Strategy) -> Result<IndexAllocator, MerkleError> {
+        if let Strategy::ShuffledBlock { block_size: 0 } = strategy {
+            return Err(MerkleError::Registry("Block size must be at least 1.".to_string()));
+        }
+        if capacity > MAX_CAPACITY {
+            return Err(MerkleError::Registry(format!(
+                "Capacity {} is more than the maximum ({}).", capacity, MAX_CAPACITY)));
+        }
+        Ok(IndexAllocator {
+            strategy,
+            issued: Bitmap::new(capacity)?,
+            revoked: Bitmap::new(capacity)?,
+            issued_count: 0,
+            cursor: 0,
+            pending: Vec::new()
+        })
+    }
+
+    /// Rebuild an allocator from its issued and revoked indices, as a log
+    /// replay does. New indices are picked by `strategy` from the ones not
+    /// yet issued.
+    pub fn restore(strategy: Strategy, issued: Bitmap, revoked: Bitmap) -> Result<IndexAllocator, MerkleError> {
+        let mut allocator = IndexAllocator::new(issued.len(), strategy)?;
+        if revoked.len() != issued.len() || revoked.iter_ones().any(|i| !issued.get_bit(i)) {
+            return Err(MerkleError::Registry("Revoked credentials must all be issued.".to_string()));
+        }
+        allocator.issued_count = issued.count_ones();
+        allocator.issued = issued;
+        allocator.revoked = revoked;
+        Ok(allocator)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.issued.len()
+    }
+
+    pub fn strategy(&self) -> &Strategy {
+        &self.strategy
+    }
+
+    pub fn issued(&self) -> &Bitmap {
+        &self.issued
+    }
+
+    pub fn revoked(&self) -> &Bitmap {
+        &self.revoked
+    }
+
+    pub fn issued_count(&self) -> usize {
+        self.issued_count
+    }
+
+    pub fn is_issued(&self, index: u64) -> bool {
+        (index as usize) < self.capacity() && self.issued.get_bit(index as usize)
+    }
+
+    /// Whether an issued credential is revoked.
+    pub fn is_revoked(&self, index: u64) -> Result<bool, MerkleError> {
+        if !self.is_issued(index) {
+            return Err(MerkleError::NotIssued(index));
+        }
+        Ok(self.revoked.get_bit(index as usize))
+    }
+
+    /// Pick an index that has never been issued, and mark it issued.
+    pub fn allocate(&mut self) -> Result<u64, MerkleError> {
+        if self.issued_count >= self.capacity() {
+            return Err(MerkleError::Registry(format!(
+                "All {} indices have been issued.", self.capacity())));
+        }
+        let index = match self.strategy {
+            Strategy::Sequential => {
+                while self.cursor < self.capacity() && self.issued.get_bit(self.cursor) {
+                    self.cursor += 1;
+                }
+                // Past the end only if the saved state was edited by hand.
+                if self.cursor >= self.capacity() { self.issued.nth_zero(0).unwrap() } else { self.cursor }
+            },
+            Strategy::Random => {
+                let free = self.capacity() - self.issued_count;
+                let n = rand::thread_rng().gen_range(0, free);
+                self.issued.nth_zero(n).unwrap()
+            },
+            Strategy::ShuffledBlock { block_size } => loop {
+                match self.pending.pop() {
+                    Some(i) if !self.issued.get_bit(i) => break i,
+                    Some(_) => continue,
+                    // Only possible if the saved state was edited by hand.
+                    None if self.cursor >= self.capacity() => break self.issued.nth_zero(0).unwrap(),
+                    None => {
+                        let end = std::cmp::min(self.cursor + block_size, self.capacity());
+                        self.pending = (self.cursor..end).collect();
+                        self.pending.shuffle(&mut rand::thread_rng());
+                        self.cursor = end;
+                    }
+                }
+            }
+        };
+        self.issued.set_bit(index);
+        self.issued_count += 1;
+        Ok(index as u64)
+    }
+
+    pub fn revoke(&mut self, index: u64) -> Result<(), MerkleError> {
+        if self.is_revoked(index)? {
+            return Err(MerkleError::Registry(format!("Credential {} is already revoked.", index)));
+        }
+        self.revoked.set_bit(index as usize);
+        Ok(())
+    }
+
+    pub fn reinstate(&mut self, index: u64) -> Result<(), MerkleError> {
+        if !self.is_revoked(index)? {
+            return Err(MerkleError::Registry(format!("Credential {} is already active.", index)));
+        }
+        self.revoked.unset_bit(index as usize);
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<Value, MerkleError> {
+        let strategy = match self.strategy {
+            Strategy::Sequential => json!({"type": "sequential"}),
+            Strategy::Random => json!({"type": "random"}),
+            Strategy::ShuffledBlock { block_size } => {
+                json!({"type": "shuffled-block", "block_size": block_size})
+            }
+        };
+        Ok(json!({
+            "version": 1,
+            "strategy": strategy,
+            "capacity": self.capacity(),
+            "issued": self.issued.to_encoded_list()?,
+            "revoked": self.revoked.to_encoded_list()?,
+            "cursor": self.cursor,
+            "pending": self.pending
+        }))
+    }
+
+    /// Load the output of to_json(). Rejects state that breaks the
+    /// allocator's invariants: bitmaps the wrong length, revoked indices
+    /// that were never issued, or a cursor or pending index out of range.
+    pub fn from_json(v: &Value) -> Result<IndexAllocator, MerkleError> {
+        let bad = |what: &str| MerkleError::Registry(format!("Bad allocator state: {}.", what));
+        let number = |field: &str| v[field].as_u64().map(|n| n as usize).ok_or_else(|| bad(field));
+        if v["version"].as_u64() != Some(1) {
+            return Err(bad("version"));
+        }
+        let strategy = match v["strategy"]["type"].as_str() {
+            Some("sequential") => Strategy::Sequential,
+            Some("random") => Strategy::Random,
+            Some("shuffled-block") => {
+                let block_size = v["strategy"]["block_size"].as_u64().ok_or_else(|| bad("block_size"))?;
+                Strategy::ShuffledBlock { block_size: block_size as usize }
+            },
+            _ => return Err(bad("strategy"))
+        };
+        let capacity = number("capacity")?;
+        let mut allocator = IndexAllocator::new(capacity, strategy)?;
+        for field in &["issued", "revoked"] {
+            let encoded = v[*field].as_str().ok_or_else(|| bad(field))?;
+            let decoded = Bitmap::from_encoded_list(encoded)?;
+            // The encoding pads to whole bytes.
+            if decoded.len() != capacity.div_ceil(8) * 8 {
+                return Err(bad(field));
+            }
+            let target = if *field == "issued" { &mut allocator.issued } else { &mut allocator.revoked };
+            for i in decoded.iter_ones() {
+                target.try_set_bit(i)?;
+            }
+        }
+        if allocator.revoked.iter_ones().any(|i| !allocator.issued.get_bit(i)) {
+            return Err(bad("revoked credentials that weren't issued"));
+        }
+        allocator.issued_count = allocator.issued.count_ones();
+        allocator.cursor = number("cursor")?;
+        if allocator.cursor > capacity {
+            return Err(bad("cursor"));
+        }
+        allocator.pending = v["pending"].as_array().ok_or_else(|| bad("pending"))?
+            .iter()
+            .map(|i| i.as_u64().map(|i| i as usize).filter(|i| *i < capacity).ok_or_else(|| bad("pending")))
+            .collect::<Result<Vec<usize>, MerkleError>>()?;
+        Ok(allocator)
+    }
+
+    /// Write the state to `path`. The new state goes to a temporary file
+    /// that's renamed over the old one, so a crash mid-write leaves the
+    /// previous state intact.
+    pub fn save(&self, path: &Path) -> Result<(), MerkleError> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&self.to_json()?)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<IndexAllocator, MerkleError> {
+        let v: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        IndexAllocator::from_json(&v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocate_all(a: &mut IndexAllocator) -> Vec<u64> {
+        let mut v = Vec::new();
+        while let Ok(i) = a.allocate() {
+            v.push(i);
+        }
+        v
+    }
+
+    #[test]
+    fn strategies_never_repeat() {
+        let mut a = IndexAllocator::new(20, Strategy::Sequential).unwrap();
+        assert_eq!(allocate_all(&mut a), (0..20).collect::<Vec<u64>>());
+
+        let mut a = IndexAllocator::new(100, Strategy::Random).unwrap();
+        let mut v = allocate_all(&mut a);
+        v.sort();
+        assert_eq!(v, (0..100).collect::<Vec<u64>>());
+
+        let mut a = IndexAllocator::new(20, Strategy::ShuffledBlock { block_size: 8 }).unwrap();
+        let v = allocate_all(&mut a);
+        for (block, range) in v.chunks(8).zip(&[0..8u64, 8..16, 16..20]) {
+            let mut block = block.to_vec();
+            block.sort();
+            assert_eq!(block, range.clone().collect::<Vec<u64>>());
+        }
+        assert_eq!(a.issued_count(), 20);
+    }
+
+    #[test]
+    fn tracks_revocations() {
+        let mut a = IndexAllocator::new(10, Strategy::Sequential).unwrap();
+        a.allocate().unwrap();
+        a.revoke(0).unwrap();
+        assert!(a.revoke(0).is_err());
+        assert!(a.revoke(1).is_err());
+        assert!(a.is_revoked(0).unwrap());
+        a.reinstate(0).unwrap();
+        assert!(a.reinstate(0).is_err());
+    }
+
+    #[test]
+    fn resumes_from_saved_state() {
+        let mut a = IndexAllocator::new(50, Strategy::ShuffledBlock { block_size: 16 }).unwrap();
+        let mut first: Vec<u64> = (0..5).map(|_| a.allocate().unwrap()).collect();
+        a.revoke(first[2]).unwrap();
+
+        let path = std::env::temp_dir().join(format!("allocator-{}.json", std::process::id()));
+        a.save(&path).unwrap();
+        let mut b = IndexAllocator::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(b, a);
+        assert!(b.is_revoked(first[2]).unwrap());
+
+        first.extend(allocate_all(&mut b));
+        first.sort();
+        assert_eq!(first, (0..50).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn rejects_bad_state() {
+        let mut a = IndexAllocator::new(20, Strategy::Sequential).unwrap();
+        a.allocate().unwrap();
+        a.revoke(0).unwrap();
+        let good = a.to_json().unwrap();
+        assert_eq!(IndexAllocator::from_json(&good).unwrap(), a);
+
+        let mut cursor = good.clone();
+        cursor["cursor"] = json!(21);
+        assert!(IndexAllocator::from_json(&cursor).is_err());
+
+        let mut never_issued = Bitmap::new(20).unwrap();
+        never_issued.set_bit(5);
+        let mut revoked = good.clone();
+        revoked["revoked"] = json!(never_issued.to_encoded_list().unwrap());
+        assert!(IndexAllocator::from_json(&revoked).is_err());
+
+        let mut length = good.clone();
+        length["issued"] = json!(Bitmap::new(64).unwrap().to_encoded_list().unwrap());
+        assert!(IndexAllocator::from_json(&length).is_err());
+
+        let mut huge = good;
+        huge["capacity"] = json!(MAX_CAPACITY + 1);
+        assert!(IndexAllocator::from_json(&huge).is_err());
+        assert!(IndexAllocator::new(MAX_CAPACITY + 1, Strategy::Random).is_err());
+    }
+}
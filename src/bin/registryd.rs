@@ -0,0 +1,456 @@
+extern crate clap;
+use clap::{Arg, App};
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// Force this binary to use jemalloc, like the others.
+extern crate jemallocator;
+
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+use merklespike::{El, MerkleError};
+use merklespike::allocator::{IndexAllocator, Strategy};
+use merklespike::encoding::ProofCodec;
+use merklespike::registry::Registry;
+use merklespike::witness::LeafUpdate;
+
+const ROUTES: &str = "\
+GET  /root              current root, depth and counts
+GET  /status-list       RevocationList2020 credential (unsigned)
+GET  /witness/N         auth path for credential N
+GET  /deltas?since=ROOT leaf updates since ROOT (hex), oldest first; 404 if
+                        ROOT is older than the history kept in memory
+GET  /snapshot          hash db snapshot (as written by Db::save)
+POST /issue             allocate an index for a new credential
+POST /revoke/N          revoke credential N
+POST /reinstate/N       undo a revocation
+POST requests need an \"Authorization: Bearer TOKEN\" header.
+Update history isn't saved: after a restart, /deltas only knows roots from then on.";
+
+fn main() {
+
+    let matches = App::new("registryd")
+        .about("\
+        \nServes a revocation registry over HTTP on localhost, as a stand-in for a real \
+        registry in integration tests.")
+        .after_help(ROUTES)
+        .arg(Arg::with_name("port")
+            .short("p")
+            .long("port")
+            .value_name("PORT")
+            .help("Port to listen on (default 8080)")
+            .takes_value(true))
+        .arg(Arg::with_name("capacity")
+            .short("c")
+            .long("capacity")
+            .value_name("N")
+            .help("How many credentials a new registry can issue (default 131072)")
+            .takes_value(true))
+        .arg(Arg::with_name("strategy")
+            .long("strategy")
+            .value_name("sequential|random|block:N")
+            .help("How a new registry allocates indices (default sequential)")
+            .takes_value(true))
+        .arg(Arg::with_name("state")
+            .short("s")
+            .long("state")
+            .value_name("FILE")
+            .help("Loads the registry from FILE if it exists, and saves it there after every change")
+            .takes_value(true))
+        .arg(Arg::with_name("token")
+            .short("t")
+            .long("token")
+            .value_name("TOKEN")
+            .help("Token for POST requests (default: $REGISTRY_TOKEN, or a random one)")
+            .takes_value(true))
+        .arg(Arg::with_name("history")
+            .long("history")
+            .value_name("N")
+            .help("How many updates to keep for /deltas (default 10000)")
+            .takes_value(true))
+        .arg(Arg::with_name("id")
+            .long("id")
+            .value_name("URL")
+            .help("id of the status list credential")
+            .takes_value(true))
+        .arg(Arg::with_name("issuer")
+            .long("issuer")
+            .value_name("DID")
+            .help("issuer of the status list credential")
+            .takes_value(true))
+        .get_matches();
+
+    let port = matches.value_of("port").unwrap_or("8080").parse::<u16>()
+        .unwrap_or_else(|_| exit_with("Bad port."));
+    let max_history = matches.value_of("history").unwrap_or("10000").parse::<usize>()
+        .unwrap_or_else(|_| exit_with("Bad history length."));
+    let state_path = matches.value_of("state").map(PathBuf::from);
+    let allocator = match &state_path {
+        Some(path) if path.exists() => IndexAllocator::load(path)
+            .unwrap_or_else(|e| exit_with(&format!("Can't load {}. {}", path.display(), e))),
+        _ => {
+            let capacity = matches.value_of("capacity").unwrap_or("131072").parse::<usize>()
+                .unwrap_or_else(|_| exit_with("Bad capacity."));
+            let strategy = parse_strategy(matches.value_of("strategy").unwrap_or("sequential"))
+                .unwrap_or_else(|| exit_with("Bad strategy."));
+            IndexAllocator::new(capacity, strategy).unwrap_or_else(|e| exit_with(&e.to_string()))
+        }
+    };
+    let token = match matches.value_of("token").map(String::from).or_else(|| env::var("REGISTRY_TOKEN").ok()) {
+        Some(token) => token,
+        None => {
+            let token = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+            println!("Token for POST requests: {}", token);
+            token
+        }
+    };
+
+    let hash_params = merklespike::make_hash_params();
+    let hash_func = merklespike::make_hash_func(&hash_params);
+    let registry = Registry::with_allocator(&hash_func, allocator)
+        .unwrap_or_else(|e| exit_with(&format!("Can't build registry. {}", e)));
+    let codec = ProofCodec::new(&hash_func, registry.depth())
+        .unwrap_or_else(|e| exit_with(&e.to_string()));
+
+    let server = Server::http(("127.0.0.1", port))
+        .unwrap_or_else(|e| exit_with(&format!("Can't listen on port {}. {}", port, e)));
+    println!("Serving a registry of depth {} ({} of {} issued) on http://127.0.0.1:{}/",
+             registry.depth(), registry.issued(), registry.capacity(), port);
+
+    let mut service = Service {
+        initial_root: registry.root().clone(),
+        registry,
+        hash_func: &hash_func,
+        codec,
+        token,
+        state_path,
+        history: VecDeque::new(),
+        max_history,
+        list_id: matches.value_of("id").unwrap_or("http://127.0.0.1/status-list").to_string(),
+        issuer: matches.value_of("issuer").unwrap_or("did:example:registryd").to_string()
+    };
+    for request in server.incoming_requests() {
+        service.respond(request);
+    }
+}
+
+fn exit_with(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+fn parse_strategy(s: &str) -> Option<Strategy> {
+    match s {
+        "sequential" => Some(Strategy::Sequential),
+        "random" => Some(Strategy::Random),
+        _ if s.starts_with("block:") => {
+            s[6..].parse::<usize>().ok().map(|block_size| Strategy::ShuffledBlock { block_size })
+        },
+        _ => None
+    }
+}
+
+/// An HTTP status and body.
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>
+}
+
+impl Reply {
+    fn json(status: u16, v: Value) -> Reply {
+        Reply { status, content_type: "application/json", body: v.to_string().into_bytes() }
+    }
+
+    fn error(status: u16, msg: &str) -> Reply {
+        Reply::json(status, json!({ "error": msg }))
+    }
+}
+
+impl From<MerkleError> for Reply {
+    fn from(e: MerkleError) -> Self {
+        let status = match e {
+            MerkleError::Registry(_) => 409,
+            MerkleError::Witness(_) | MerkleError::NotIssued(_) => 404,
+            _ => 500
+        };
+        Reply::error(status, &e.to_string())
+    }
+}
+
+struct Service<'a> {
+    registry: Registry<'a>,
+    hash_func: &'a PoseidonHash8<'a>,
+    codec: ProofCodec,
+    token: String,
+    state_path: Option<PathBuf>,
+    /// The root before the oldest update in history: the root we started
+    /// with, until history fills up and old updates are dropped.
+    initial_root: El,
+    history: VecDeque<LeafUpdate>,
+    max_history: usize,
+    list_id: String,
+    issuer: String
+}
+
+impl<'a> Service<'a> {
+    fn respond(&mut self, request: Request) {
+        let auth = request.headers().iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str().to_string());
+        let reply = self.handle(request.method(), request.url(), auth.as_deref())
+            .unwrap_or_else(Reply::from);
+        println!("{} {} -> {}", request.method(), request.url(), reply.status);
+        let header = Header::from_bytes(&b"Content-Type"[..], reply.content_type.as_bytes()).unwrap();
+        let response = Response::from_data(reply.body).with_status_code(reply.status).with_header(header);
+        if let Err(e) = request.respond(response) {
+            eprintln!("Can't send response. {}", e);
+        }
+    }
+
+    fn handle(&mut self, method: &Method, url: &str, auth: Option<&str>) -> Result<Reply, MerkleError> {
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], &url[i + 1..]),
+            None => (url, "")
+        };
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            (Method::Get, ["root"]) => Ok(Reply::json(200, self.root_json())),
+            (Method::Get, ["status-list"]) => {
+                Ok(Reply::json(200, self.registry.status_list(&self.list_id, &self.issuer)?))
+            },
+            (Method::Get, ["witness", n]) => match n.parse::<u64>() {
+                Ok(index) => {
+                    let witness = self.registry.witness(index)?;
+                    Ok(Reply::json(200, json!({
+                        "root": self.registry.root().to_hex(),
+                        "revoked": witness.leaf.is_one(),
+                        "witness": self.codec.encode_json(&witness)?
+                    })))
+                },
+                Err(_) => Ok(Reply::error(400, "Bad index."))
+            },
+            (Method::Get, ["deltas"]) => {
+                let since = query.split('&').find_map(|kv| kv.strip_prefix("since="));
+                match since {
+                    Some(since) => Ok(self.deltas(since)),
+                    None => Ok(Reply::error(400, "Expected ?since=ROOT."))
+                }
+            },
+            (Method::Get, ["snapshot"]) => self.snapshot(),
+            (Method::Post, _) if !self.authorized(auth) => Ok(Reply::error(401, "Bad or missing token.")),
+            (Method::Post, ["issue"]) => {
+                let index = self.change(|registry| registry.issue())?;
+                Ok(Reply::json(200, json!({ "index": index, "root": self.registry.root().to_hex() })))
+            },
+            (Method::Post, [action, n]) if *action == "revoke" || *action == "reinstate" => {
+                let index = match n.parse::<u64>() {
+                    Ok(index) => index,
+                    Err(_) => return Ok(Reply::error(400, "Bad index."))
+                };
+                let revoke = *action == "revoke";
+                let hash_func = self.hash_func;
+                let update = self.change(|registry| {
+                    if revoke {
+                        registry.revoke(index)?;
+                    } else {
+                        registry.reinstate(index)?;
+                    }
+                    LeafUpdate::new(registry.tree(), registry.db(), index, hash_func)
+                })?;
+                let body = json!({ "root": self.registry.root().to_hex(), "update": update.to_json() });
+                self.remember(update);
+                Ok(Reply::json(200, body))
+            },
+            _ => Ok(Reply::error(404, "No such route."))
+        }
+    }
+
+    fn root_json(&self) -> Value {
+        json!({
+            "root": self.registry.root().to_hex(),
+            "depth": self.registry.depth(),
+            "capacity": self.registry.capacity(),
+            "issued": self.registry.issued(),
+            "revoked": self.registry.revoked().count_ones()
+        })
+    }
+
+    /// Apply a change to the registry, then save. If saving fails, put the
+    /// registry back as it was, so it never runs ahead of the state file.
+    fn change<T, F>(&mut self, f: F) -> Result<T, MerkleError>
+        where F: FnOnce(&mut Registry<'a>) -> Result<T, MerkleError> {
+        let before = self.state_path.as_ref().map(|_| self.registry.allocator().clone());
+        let result = f(&mut self.registry)?;
+        if let Err(e) = self.save() {
+            if let Some(before) = before {
+                self.registry = Registry::with_allocator(self.hash_func, before)?;
+            }
+            return Err(e);
+        }
+        Ok(result)
+    }
+
+    /// Add an update to history, dropping the oldest if it's full.
+    fn remember(&mut self, update: LeafUpdate) {
+        self.history.push_back(update);
+        while self.history.len() > self.max_history {
+            if let Some(dropped) = self.history.pop_front() {
+                self.initial_root = dropped.root().clone();
+            }
+        }
+    }
+
+    /// Every update after the last time the root was `since`. A holder can
+    /// apply them in order to a witness for that root.
+    fn deltas(&self, since: &str) -> Reply {
+        let start = if let Some(i) = self.history.iter().rposition(|u| u.root().to_hex().eq_ignore_ascii_case(since)) {
+            i + 1
+        } else if self.initial_root.to_hex().eq_ignore_ascii_case(since) {
+            0
+        } else {
+            return Reply::error(404, "Unknown root; fetch a fresh witness instead.");
+        };
+        let updates: Vec<Value> = self.history.range(start..).map(|u| u.to_json()).collect();
+        Reply::json(200, json!({ "root": self.registry.root().to_hex(), "updates": updates }))
+    }
+
+    fn snapshot(&self) -> Result<Reply, MerkleError> {
+        let path = env::temp_dir().join(format!("registryd-{}.zip", process::id()));
+        self.registry.db().save(&path, self.registry.root())
+            .map_err(|e| MerkleError::Db(format!("{:?}", e)))?;
+        let body = fs::read(&path)?;
+        fs::remove_file(&path).ok();
+        Ok(Reply { status: 200, content_type: "application/zip", body })
+    }
+
+    fn authorized(&self, auth: Option<&str>) -> bool {
+        match auth.and_then(|a| a.strip_prefix("Bearer ")) {
+            // Compare every byte, so timing doesn't reveal how much matched.
+            Some(given) => given.len() == self.token.len() &&
+                given.bytes().zip(self.token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0,
+            None => false
+        }
+    }
+
+    fn save(&self) -> Result<(), MerkleError> {
+        match &self.state_path {
+            Some(path) => self.registry.allocator().save(Path::new(path)),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "Bearer secret";
+
+    fn service<'a>(hash_func: &'a PoseidonHash8<'a>, max_history: usize) -> Service<'a> {
+        let registry = Registry::new(hash_func, 64).unwrap();
+        Service {
+            initial_root: registry.root().clone(),
+            codec: ProofCodec::new(hash_func, registry.depth()).unwrap(),
+            registry,
+            hash_func,
+            token: "secret".to_string(),
+            state_path: None,
+            history: VecDeque::new(),
+            max_history,
+            list_id: "http://127.0.0.1/status-list".to_string(),
+            issuer: "did:example:registryd".to_string()
+        }
+    }
+
+    fn call(service: &mut Service, method: Method, url: &str, auth: Option<&str>) -> (u16, Value) {
+        let reply = service.handle(&method, url, auth).unwrap_or_else(Reply::from);
+        (reply.status, serde_json::from_slice(&reply.body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn routes() {
+        let hash_params = merklespike::make_hash_params();
+        let hash_func = merklespike::make_hash_func(&hash_params);
+        let mut service = service(&hash_func, 100);
+        let empty_root = service.registry.root().to_hex();
+
+        let (status, root) = call(&mut service, Method::Get, "/root", None);
+        assert_eq!((status, root["depth"].as_u64()), (200, Some(2)));
+        assert_eq!(call(&mut service, Method::Post, "/issue", None).0, 401);
+        assert_eq!(call(&mut service, Method::Post, "/issue", Some("Bearer wrong")).0, 401);
+        let (status, issued) = call(&mut service, Method::Post, "/issue", Some(TOKEN));
+        assert_eq!((status, issued["index"].as_u64()), (200, Some(0)));
+
+        assert_eq!(call(&mut service, Method::Post, "/revoke/0", Some(TOKEN)).0, 200);
+        assert_eq!(call(&mut service, Method::Post, "/revoke/0", Some(TOKEN)).0, 409);
+        assert_eq!(call(&mut service, Method::Post, "/revoke/x", Some(TOKEN)).0, 400);
+        assert_eq!(call(&mut service, Method::Post, "/reinstate/0", Some(TOKEN)).0, 200);
+        assert_eq!(call(&mut service, Method::Post, "/reinstate/0", Some(TOKEN)).0, 409);
+        assert_eq!(call(&mut service, Method::Post, "/revoke/0", Some(TOKEN)).0, 200);
+        assert_eq!(call(&mut service, Method::Post, "/reinstate/5", Some(TOKEN)).0, 404);
+
+        let (status, witness) = call(&mut service, Method::Get, "/witness/0", None);
+        assert_eq!((status, witness["revoked"].as_bool()), (200, Some(true)));
+        assert_eq!(call(&mut service, Method::Get, "/witness/5", None).0, 404);
+        assert_eq!(call(&mut service, Method::Get, "/witness/100000", None).0, 404);
+        let path = service.codec.decode_json(&witness["witness"]).unwrap();
+        assert!(service.registry.verify(&path).unwrap());
+
+        let (status, deltas) = call(&mut service, Method::Get, &format!("/deltas?since={}", empty_root), None);
+        assert_eq!((status, deltas["updates"].as_array().map(|u| u.len())), (200, Some(1)));
+        let current = service.registry.root().to_hex();
+        let (_, deltas) = call(&mut service, Method::Get, &format!("/deltas?since={}", current), None);
+        assert_eq!(deltas["updates"].as_array().map(|u| u.len()), Some(0));
+        assert_eq!(call(&mut service, Method::Get, "/deltas?since=abcd", None).0, 404);
+        assert_eq!(call(&mut service, Method::Get, "/deltas", None).0, 400);
+
+        let (status, list) = call(&mut service, Method::Get, "/status-list", None);
+        assert_eq!((status, list["issuer"].as_str()), (200, Some("did:example:registryd")));
+        assert_eq!(call(&mut service, Method::Get, "/nowhere", None).0, 404);
+        assert_eq!(Reply::from(MerkleError::Witness("gone".to_string())).status, 404);
+    }
+
+    #[test]
+    fn history_drops_oldest() {
+        let hash_params = merklespike::make_hash_params();
+        let hash_func = merklespike::make_hash_func(&hash_params);
+        let mut service = service(&hash_func, 2);
+        let empty_root = service.registry.root().to_hex();
+        let mut roots = Vec::new();
+        for i in 0..3 {
+            call(&mut service, Method::Post, "/issue", Some(TOKEN));
+            let (_, body) = call(&mut service, Method::Post, &format!("/revoke/{}", i), Some(TOKEN));
+            roots.push(body["root"].as_str().unwrap().to_string());
+        }
+        assert_eq!(service.history.len(), 2);
+        assert_eq!(call(&mut service, Method::Get, &format!("/deltas?since={}", empty_root), None).0, 404);
+        let (status, deltas) = call(&mut service, Method::Get, &format!("/deltas?since={}", roots[0]), None);
+        assert_eq!((status, deltas["updates"].as_array().map(|u| u.len())), (200, Some(2)));
+    }
+
+    #[test]
+    fn failed_save_leaves_registry_unchanged() {
+        let hash_params = merklespike::make_hash_params();
+        let hash_func = merklespike::make_hash_func(&hash_params);
+        let mut service = service(&hash_func, 100);
+        call(&mut service, Method::Post, "/issue", Some(TOKEN));
+        let root = service.registry.root().clone();
+
+        service.state_path = Some(env::temp_dir().join("registryd-no-such-dir").join("state.json"));
+        assert_eq!(call(&mut service, Method::Post, "/issue", Some(TOKEN)).0, 500);
+        assert_eq!(service.registry.issued(), 1);
+        assert_eq!(call(&mut service, Method::Post, "/revoke/0", Some(TOKEN)).0, 500);
+        assert!(!service.registry.is_revoked(0).unwrap());
+        assert_eq!(service.registry.root(), &root);
+        assert!(service.history.is_empty());
+    }
+}
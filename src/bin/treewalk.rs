@@ -1,8 +1,14 @@
 extern crate clap;
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
 use bulletproofs_amcl::r1cs::gadgets::helper_constraints::sparse_merkle_tree_8_ary::DbVal8ary;
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process;
 
 #[macro_use] extern crate lazy_static;
 
@@ -13,7 +19,12 @@ extern crate jemallocator;
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-use merklespike::{El, Db, Tree};
+use merklespike::{El, Db, Tree, MerkleError};
+use merklespike::bitmap::Bitmap;
+use merklespike::export::{export_subtree, hash_text};
+use merklespike::integrity::check_integrity;
+use merklespike::proof::{AuthPath, leaf_index_to_digits};
+use merklespike::stats::TreeStats;
 
 fn main() {
 
@@ -26,85 +37,420 @@ fn main() {
             .value_name("N")
             .help("Sets depth of merkle tree")
             .takes_value(true))
+        .arg(Arg::with_name("load")
+            .short("l")
+            .long("load")
+            .value_name("SNAPSHOT")
+            .help("Loads a tree from a hash db snapshot (as written by Db::save)")
+            .takes_value(true)
+            .conflicts_with_all(&["bitmap", "depth"]))
+        .arg(Arg::with_name("bitmap")
+            .short("b")
+            .long("bitmap")
+            .value_name("REVLIST")
+            .help("Builds a tree from a RevocationList2020 credential")
+            .takes_value(true))
+        .arg(Arg::with_name("full")
+            .short("f")
+            .long("full-hashes")
+            .help("Shows full hashes instead of the last 8 hex digits"))
+        .arg(Arg::with_name("script")
+            .short("s")
+            .long("script")
+            .value_name("FILE")
+            .help("Runs commands from FILE (or - for stdin) without prompting; stops at the first error")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("check")
+            .about("Verifies every node of a snapshot against its children's hashes, then exits")
+            .arg(Arg::with_name("SNAPSHOT")
+                .help("A hash db snapshot (as written by Db::save)")
+                .required(true))
+            .arg(Arg::with_name("depth")
+                .short("d")
+                .long("depth")
+                .value_name("N")
+                .help("Depth of the tree, if it can't be inferred from the snapshot")
+                .takes_value(true)))
         .get_matches();
 
-    let depth: usize = matches.value_of("depth").unwrap_or("2").parse::<usize>().unwrap();
+    if let Some(check) = matches.subcommand_matches("check") {
+        check_snapshot(check.value_of("SNAPSHOT").unwrap(), check.value_of("depth"));
+    }
+
+    let depth = matches.value_of("depth").map(|d| d.parse::<usize>()
+        .unwrap_or_else(|_| exit_with(&format!("Bad depth {}.", d))));
 
     let mut db = merklespike::make_db();
     let hash_params = merklespike::make_hash_params();
     let hash_func = merklespike::make_hash_func(&hash_params);
-    let mut tree = merklespike::Tree::new(
-        &hash_func, depth as usize, &mut db).unwrap();
+    let tree = if let Some(snapshot) = matches.value_of("load") {
+        let tree = merklespike::load_tree(Path::new(snapshot), &hash_func, &mut db)
+            .unwrap_or_else(|e| exit_with(&format!("Can't load {}. {}", snapshot, e)));
+        println!("Loaded a sparse 8-ary Merkle tree from {}.", snapshot);
+        tree
+    } else if let Some(revlist) = matches.value_of("bitmap") {
+        let b = File::open(revlist).map_err(MerkleError::from)
+            .and_then(Bitmap::from_revlist2020)
+            .unwrap_or_else(|e| exit_with(&format!("Can't read {}. {}", revlist, e)));
+        let depth = depth.unwrap_or_else(|| merklespike::tree_depth_for(b.len()));
+        let tree = merklespike::build_tree_from_bitmap(depth, &b, &hash_func, &mut db)
+            .unwrap_or_else(|e| exit_with(&format!("Can't build tree. {}", e)));
+        println!("Built a sparse 8-ary Merkle tree from {} ({} of {} bits set).",
+                 revlist, b.count_ones(), b.len());
+        tree
+    } else {
+        let tree = merklespike::Tree::new(&hash_func, depth.unwrap_or(2), &mut db)
+            .unwrap_or_else(|e| exit_with(&format!("Can't create tree. {:?}", e)));
+        println!("Generated a sparse 8-ary Merkle tree.");
+        tree
+    };
 
-    println!("Generated a sparse 8-ary Merkle tree.\n  depth = {}\n  node count = {}",
-            depth, db.len());
+    println!("  depth = {}\n  node count = {}", tree.depth, db.len());
 
-    let mut last_path = "root".to_string();
-    let mut path = "".to_string();
-    let mut short_circuit = true;
-    loop {
-        io::stdout().write(format!("\n{}> ", last_path).as_bytes()).ok();
-        io::stdout().flush().ok();
-        let mut cmd = String::new();
-        // Skip input first time through loop; always show root of tree
-        if short_circuit {
-            cmd = last_path.clone();
-            io::stdout().write(b"root\n").ok();
-            short_circuit = false;
-        } else {
-            if io::stdin().read_line(&mut cmd).is_err() {
-                break;
+    let mut session = Session {
+        tree,
+        db,
+        hash_func: &hash_func,
+        counts: HashMap::new(),
+        last_path: "root".to_string(),
+        last_leaf: None,
+        full_hashes: matches.is_present("full")
+    };
+
+    // Always start by showing the root of the tree.
+    print!("\nroot> root\n");
+    if let Err(e) = session.show("root") {
+        exit_with(&e);
+    }
+
+    match matches.value_of("script") {
+        Some(script) => {
+            let reader: Box<dyn BufRead> = if script == "-" {
+                Box::new(BufReader::new(io::stdin()))
+            } else {
+                let f = File::open(script)
+                    .unwrap_or_else(|e| exit_with(&format!("Can't open {}. {}", script, e)));
+                Box::new(BufReader::new(f))
+            };
+            for (line_num, line) in reader.lines().enumerate() {
+                let line = line.unwrap_or_else(|e| exit_with(&format!("Can't read script. {}", e)));
+                // Echo each command so the output reads like an interactive session.
+                println!("\n{}> {}", session.last_path, line.trim());
+                match session.run(&line) {
+                    Ok(Outcome::Quit) => return,
+                    Ok(Outcome::Continue) => (),
+                    Err(e) => exit_with(&format!("Line {}: {}", line_num + 1, e))
+                }
+            }
+        },
+        None => {
+            loop {
+                print!("\n{}> ", session.last_path);
+                io::stdout().flush().ok();
+                let mut cmd = String::new();
+                match io::stdin().read_line(&mut cmd) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => ()
+                }
+                match session.run(&cmd) {
+                    Ok(Outcome::Quit) => return,
+                    Ok(Outcome::Continue) => (),
+                    Err(e) => println!("Error: {}", e)
+                }
             }
         }
-        let token = cmd.trim();
-        if let Ok(child_num ) = token.parse::<u8>() {
-            if child_num < 8 {
-                path = last_path.clone();
-                path.push('/');
-                path.push_str(token);
-            } else {
-                println!("No such child.");
-                continue
+    }
+}
+
+/// Run the integrity checker on a snapshot and exit: 0 if it's sound, 1 if not.
+fn check_snapshot(snapshot: &str, depth: Option<&str>) -> ! {
+    let hash_params = merklespike::make_hash_params();
+    let hash_func = merklespike::make_hash_func(&hash_params);
+    let mut db = merklespike::make_db();
+    let root = db.load(Path::new(snapshot))
+        .unwrap_or_else(|e| exit_with(&format!("Can't load {}. {:?}", snapshot, e)));
+    let depth = match depth {
+        Some(d) => d.parse::<usize>().unwrap_or_else(|_| exit_with(&format!("Bad depth {}.", d))),
+        None => merklespike::infer_depth(&root, &db)
+    };
+    println!("Checking {} (depth {}, {} nodes).", snapshot, depth, db.len());
+    let report = check_integrity(&root, depth, &db, &hash_func)
+        .unwrap_or_else(|e| exit_with(&format!("Can't check {}. {}", snapshot, e)));
+    print!("{}", report);
+    process::exit(if report.is_ok() { 0 } else { 1 });
+}
+
+const HELP: &str = "\
+Navigation:
+  N               go to child N (0-7) of the current node
+  /a/b/c          go to an absolute path
+  up, root        go to the parent node, or to the root (and show its full hash)
+  leaf N          go to leaf N and show every node on its path
+Changes:
+  set N, i N      set leaf N to 1
+  clear N         set leaf N to 0
+Checks:
+  verify [N]      recompute the root from leaf N's path (default: the last leaf)
+  check           recompute the hash of every node and report any that don't match
+  stats           audit the tree: nodes, sharing and size per level
+  find HEX        list nodes whose hash, or one of whose children, ends in HEX
+  full [on|off]   toggle between full and abbreviated hashes
+  save FILE       save the hash db and root to FILE
+  export dot|json [LEVELS [FILE]]
+                  export the subtree under the current node as Graphviz or JSON
+Other:
+  help, quit, q";
+
+/// find stops after this many matches.
+const MAX_MATCHES: usize = 50;
+
+enum Outcome {
+    Continue,
+    Quit
+}
+
+/// The state of one treewalk session: the tree being browsed, plus where
+/// we are in it.
+struct Session<'a> {
+    tree: Tree<'a>,
+    db: Db,
+    hash_func: &'a PoseidonHash8<'a>,
+    // Non-zero leaf counts, keyed by node hash. A hash always describes the
+    // same subtree, so entries never go stale as the tree changes.
+    counts: LeafCounts,
+    last_path: String,
+    last_leaf: Option<u64>,
+    full_hashes: bool
+}
+
+impl<'a> Session<'a> {
+
+    /// Parse and run one command line.
+    fn run(&mut self, line: &str) -> Result<Outcome, String> {
+        let token = line.trim();
+        let mut words = token.split_whitespace();
+        let cmd = match words.next() {
+            Some(cmd) => cmd,
+            None => return Ok(Outcome::Continue)
+        };
+        let args: Vec<&str> = words.collect();
+        let max_args = if cmd == "export" { 3 } else { 1 };
+        if args.len() > max_args {
+            return Err(format!("Too many arguments to \"{}\".", cmd));
+        }
+        let arg = args.first().cloned();
+        if let Ok(child_num) = cmd.parse::<u8>() {
+            if child_num >= 8 {
+                return Err("No such child.".to_string());
             }
-        } else if token.starts_with('/') {
-            path = token.to_string();
-        } else if token.starts_with("i ") {
-            let idx = token[2..].parse::<u64>().unwrap();
-            tree.update(&El::from(idx), El::one(), &mut db).ok();
-            path = last_path.clone();
+            let path = format!("{}/{}", self.last_path, child_num);
+            self.show(&path)?;
+        } else if cmd.starts_with('/') {
+            self.show(cmd)?;
         } else {
-            match token {
+            match cmd {
                 "up" => {
-                    if let Some(i) = last_path.rfind('/') {
-                        path = last_path[..i].to_string();
-                    }
+                    let path = match self.last_path.rfind('/') {
+                        Some(i) => self.last_path[..i].to_string(),
+                        None => self.last_path.clone()
+                    };
+                    self.show(&path)?;
                 },
                 "root" => {
-                    path = token.to_string();
+                    println!("Root: {}", hash_text(&self.tree.root, true));
+                    self.show("root")?;
+                },
+                "i" | "set" => {
+                    let idx = self.parse_leaf(arg)?;
+                    self.tree.update(&El::from(idx), El::one(), &mut self.db)
+                        .map_err(|e| format!("Can't set leaf {}. {:?}", idx, e))?;
+                    let path = self.last_path.clone();
+                    self.show(&path)?;
+                },
+                "clear" => {
+                    let idx = self.parse_leaf(arg)?;
+                    self.tree.update(&El::from(idx), El::zero(), &mut self.db)
+                        .map_err(|e| format!("Can't clear leaf {}. {:?}", idx, e))?;
+                    let path = self.last_path.clone();
+                    self.show(&path)?;
+                },
+                "leaf" => {
+                    let idx = self.parse_leaf(arg)?;
+                    let depth = self.tree.depth;
+                    let digits = leaf_index_to_digits(idx, depth);
+                    print_leaf_path(&self.tree, &self.db, &digits, self.full_hashes);
+                    self.last_leaf = Some(idx);
+                    let mut path = "root".to_string();
+                    for d in &digits[..depth - 1] {
+                        path.push('/');
+                        path.push_str(&d.to_string());
+                    }
+                    self.show(&path)?;
+                },
+                "verify" => {
+                    let idx = match arg {
+                        Some(_) => self.parse_leaf(arg)?,
+                        None => self.last_leaf
+                            .ok_or("Which leaf? Use \"leaf N\" or \"verify N\".".to_string())?
+                    };
+                    let auth_path = AuthPath::new(&self.tree, &self.db, idx)
+                        .map_err(|e| e.to_string())?;
+                    let computed = auth_path.compute_root(self.hash_func)
+                        .map_err(|e| e.to_string())?;
+                    println!("Leaf {} = {}", idx, hash_text(&auth_path.leaf, true));
+                    println!("Computed root = {}", hash_text(&computed, true));
+                    println!("    Tree root = {}", hash_text(&self.tree.root, true));
+                    if computed != self.tree.root {
+                        return Err("Path doesn't verify.".to_string());
+                    }
+                    println!("Path verifies.");
                 },
-                "help" => {
-                    println!("Enter \"up\", \"quit\", child num, or abs path like /1/3/7: ");
-                    continue
+                "check" => {
+                    let report = check_integrity(&self.tree.root, self.tree.depth, &self.db, self.hash_func)
+                        .map_err(|e| e.to_string())?;
+                    print!("{}", report);
+                    if !report.is_ok() {
+                        return Err("Tree is corrupt.".to_string());
+                    }
+                },
+                "stats" => {
+                    let stats = TreeStats::new(&self.tree, &self.db, self.hash_func)
+                        .map_err(|e| e.to_string())?;
+                    print!("{}", stats);
+                },
+                "save" => {
+                    let file = arg.ok_or("Save to which file?".to_string())?;
+                    self.db.save(Path::new(file), &self.tree.root)
+                        .map_err(|e| format!("Can't save {}. {:?}", file, e))?;
+                    println!("Saved {} nodes to {}.", self.db.len(), file);
+                },
+                "export" => self.export(&args)?,
+                "full" => {
+                    self.full_hashes = match arg {
+                        Some("on") => true,
+                        Some("off") => false,
+                        None => !self.full_hashes,
+                        Some(other) => return Err(format!("Expected on or off, not \"{}\".", other))
+                    };
+                    println!("Full hashes {}.", if self.full_hashes { "on" } else { "off" });
                 },
-                "quit" => return,
-                "q" => return,
-                _ => {
-                    println!("Huh?");
-                    continue
+                "find" => {
+                    let suffix = arg.ok_or("Find which hex suffix?".to_string())?;
+                    if suffix.is_empty() || !suffix.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(format!("\"{}\" isn't hex.", suffix));
+                    }
+                    let matches = self.find(&suffix.to_uppercase());
+                    for m in &matches {
+                        println!("{}", m);
+                    }
+                    if matches.is_empty() {
+                        println!("No node or child ends with {}.", suffix);
+                    } else if matches.len() >= MAX_MATCHES {
+                        println!("(Stopped after {} matches.)", matches.len());
+                    }
+                },
+                "help" => println!("{}", HELP),
+                "quit" | "q" => return Ok(Outcome::Quit),
+                _ => return Err(format!("Huh? Unknown command \"{}\"; try \"help\".", cmd))
+            }
+        }
+        Ok(Outcome::Continue)
+    }
+
+    /// Walk every node reachable from the root, listing those whose key
+    /// or children end with `suffix` (uppercase hex), up to MAX_MATCHES.
+    /// Subtrees shared by several parents are searched once, under the
+    /// first path that reaches them.
+    fn find(&self, suffix: &str) -> Vec<String> {
+        let mut visited: HashSet<Vec<u8>> = HashSet::new();
+        let mut stack: Vec<(El, String)> = vec![(self.tree.root.clone(), "root".to_string())];
+        let mut matches = Vec::new();
+        while let Some((key, path)) = stack.pop() {
+            if !visited.insert(key.to_bytes()) {
+                continue
+            }
+            let node = match get_node(&key, &self.db) {
+                Some(node) => node,
+                None => continue
+            };
+            if key.to_hex().to_uppercase().ends_with(suffix) {
+                matches.push(format!("{} = {}", path, hash_text(&key, self.full_hashes)));
+            }
+            for (i, child) in node.iter().enumerate() {
+                if !child.is_zero() && child.to_hex().to_uppercase().ends_with(suffix) {
+                    matches.push(format!("{}: child {} = {}", path, i, hash_text(child, self.full_hashes)));
                 }
             }
+            if matches.len() >= MAX_MATCHES {
+                break
+            }
+            for (i, child) in node.iter().enumerate().rev() {
+                stack.push((child.clone(), format!("{}/{}", path, i)));
+            }
         }
-        if let Some((preamble, node)) = find_node_from_path(&tree, &db, path.as_str()) {
-            last_path = path.clone();
-            dump(Some(preamble), &node, &db);
-        } else {
-            println!("No such path.");
+        matches
+    }
+
+    /// export dot|json [LEVELS [FILE]]: write the subtree under the current
+    /// node, LEVELS deep (default 2), to FILE or stdout.
+    fn export(&self, args: &[&str]) -> Result<(), String> {
+        let format = args.first().cloned().ok_or("Export as dot or json?".to_string())?;
+        let levels = match args.get(1) {
+            Some(n) => n.parse::<usize>().map_err(|_| format!("Bad level count \"{}\".", n))?,
+            None => 2
+        };
+        let (_, node, digits) = find_node_from_path(&self.tree, &self.db, &self.last_path, false)?;
+        let height = self.tree.depth - digits.len();
+        let subtree = export_subtree(&self.db, self.hash_func, &node, &digits, height, levels)
+            .map_err(|e| e.to_string())?;
+        let text = match format {
+            "dot" => subtree.to_dot(),
+            "json" => serde_json::to_string_pretty(&subtree.to_json()).unwrap(),
+            _ => return Err(format!("Unknown export format \"{}\"; use dot or json.", format))
+        };
+        match args.get(2) {
+            Some(file) => {
+                fs::write(file, text).map_err(|e| format!("Can't write {}. {}", file, e))?;
+                println!("Exported {} to {}.", self.last_path, file);
+            },
+            None => println!("{}", text)
         }
+        Ok(())
+    }
+
+    fn parse_leaf(&self, arg: Option<&str>) -> Result<u64, String> {
+        let arg = arg.ok_or("Expected a leaf index.".to_string())?;
+        let idx = arg.parse::<u64>().map_err(|_| format!("Bad leaf index \"{}\".", arg))?;
+        // Every u64 fits in a tree of depth 22 or more.
+        if let Some(capacity) = 8u64.checked_pow(self.tree.depth as u32) {
+            if idx >= capacity {
+                return Err(format!("Leaf {} is beyond a tree of depth {} (capacity {}).",
+                                   idx, self.tree.depth, capacity));
+            }
+        }
+        Ok(idx)
+    }
+
+    /// Navigate to `path` and dump the node there.
+    fn show(&mut self, path: &str) -> Result<(), String> {
+        let (preamble, node, digits) = find_node_from_path(
+            &self.tree, &self.db, path, self.full_hashes)?;
+        self.last_path = path.to_string();
+        dump(Some(preamble), &node, &digits, self.tree.depth, &self.db, &mut self.counts,
+             self.full_hashes);
+        Ok(())
     }
 }
 
+type LeafCounts = HashMap<Vec<u8>, u64>;
 
-fn dump(preamble: Option<Vec<PathSegment>>, key: &El, db: &Db) {
+fn exit_with(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+fn dump(preamble: Option<Vec<PathSegment>>, key: &El, digits: &[usize], depth: usize,
+        db: &Db, counts: &mut LeafCounts, full: bool) {
     let mut indenter = "".to_string();
     if let Some(preamble) = preamble {
         for item in preamble {
@@ -113,20 +459,91 @@ fn dump(preamble: Option<Vec<PathSegment>>, key: &El, db: &Db) {
             indenter.push_str("  ");
         }
     }
+    let height = depth - digits.len();
+    let (first, last) = leaf_range(digits, depth);
+    println!("{}covers leaves {}..={}; {} set", indenter, first, last,
+             count_set_leaves(key, height, db, counts));
     if let Some(node) = get_node(key, db) {
         indenter.push_str(" ");
         println!("{}  |", indenter.as_str());
         if has_children(&node) {
+            let mut child_digits = digits.to_vec();
+            child_digits.push(0);
             for i in 0..8 {
                 let more_txt = if found_with_children(&node[i], db) { "+" } else { "-" };
-                println!("{}{} {}: {}", indenter, more_txt, i, get_key_text(&node[i], false));
+                child_digits[digits.len()] = i;
+                let (first, last) = leaf_range(&child_digits, depth);
+                println!("{}{} {}: {}  [{}..={}] {} set", indenter, more_txt, i,
+                         hash_text(&node[i], full), first, last,
+                         count_set_leaves(&node[i], height - 1, db, counts));
             }
         } else {
             println!("{}  .", indenter.as_str());
         }
     } else {
-        println!("{} not found in map!", get_key_text(key, false));
+        println!("{} not found in map!", hash_text(key, full));
+    }
+}
+
+/// Print all 8 children of every node from the root down to a leaf, marking
+/// the one on the path with *.
+fn print_leaf_path(tree: &Tree, db: &Db, digits: &[usize], full: bool) {
+    let mut key = tree.root.clone();
+    let mut path = "".to_string();
+    for d in digits {
+        if let Some(node) = get_node(&key, db) {
+            let children: Vec<String> = node.iter().enumerate().map(|(i, c)| {
+                let mark = if i == *d { "*" } else { " " };
+                format!("{}{}", mark, hash_text(c, full))
+            }).collect();
+            println!("{:<16} {}", if path.is_empty() { "root" } else { path.as_str() },
+                     children.join(" "));
+            key = node[*d].clone();
+            path.push('/');
+            path.push_str(&d.to_string());
+        } else {
+            println!("{} not found in map!", hash_text(&key, full));
+            return
+        }
+    }
+    println!("{:<16} {}", path, hash_text(&key, full));
+}
+
+/// The first and last leaf index under the node reached by following
+/// `digits` (most significant first) down from the root. Indices past
+/// u64::MAX (in trees deeper than 21) are clamped to it.
+fn leaf_range(digits: &[usize], depth: usize) -> (u64, u64) {
+    let mut first: u64 = 0;
+    for d in digits {
+        first = first.saturating_mul(8).saturating_add(*d as u64);
+    }
+    match 8u64.checked_pow((depth - digits.len()) as u32) {
+        Some(span) => {
+            let first = first.saturating_mul(span);
+            (first, first.saturating_add(span - 1))
+        },
+        None => (if first == 0 { 0 } else { u64::MAX }, u64::MAX)
+    }
+}
+
+/// How many non-zero leaves lie under the node `key`, which sits `height`
+/// levels above the leaves.
+fn count_set_leaves(key: &El, height: usize, db: &Db, counts: &mut LeafCounts) -> u64 {
+    if height == 0 {
+        return if key.is_zero() { 0 } else { 1 };
     }
+    let key_bytes = key.to_bytes();
+    if let Some(n) = counts.get(&key_bytes) {
+        return *n;
+    }
+    let mut n = 0;
+    if let Some(node) = get_node(key, db) {
+        for child in node.iter() {
+            n += count_set_leaves(child, height - 1, db, counts);
+        }
+    }
+    counts.insert(key_bytes, n);
+    n
 }
 
 fn get_node(el: & El, db: & Db) -> Option<DbVal8ary> {
@@ -154,19 +571,6 @@ fn has_children(node: &DbVal8ary) -> bool {
     false
 }
 
-fn get_key_text(el: &El, full: bool) -> String {
-    if el.is_zero() {
-        "0".to_string()
-    } else {
-        let hex = el.to_hex();
-        if full {
-            hex
-        } else {
-            format!("...{}", &hex[hex.len() - 8..])
-        }
-    }
-}
-
 struct PathSegment {
     pub descrip: String,
     pub key: String,
@@ -175,40 +579,119 @@ struct PathSegment {
 /// Given a human-entered path string like "/3/7", find the corresponding
 /// internal path through the tree to the node, plus the FieldElement that
 /// acts as the lookup key for the node.
-fn find_node_from_path(tree: & Tree, db: & Db, path: &str) -> Option<(Vec<PathSegment>, El)> {
+/// Hashes are abbreviated except for the last segment, unless `full` is set.
+fn find_node_from_path(tree: & Tree, db: & Db, path: &str, full: bool)
+    -> Result<(Vec<PathSegment>, El, Vec<usize>), String> {
     use regex::Regex;
 
     lazy_static! {
         static ref RE: Regex = Regex::new(r"(\d+)").unwrap();
     }
     let mut lookup_key = tree.root.clone();
-    let mut current= get_node(&lookup_key, &db).unwrap();
+    let mut current = get_node(&lookup_key, db)
+        .ok_or(format!("Root {} not found in map!", hash_text(&lookup_key, true)))?;
     let mut ids: Vec<PathSegment> = Vec::new();
+    let mut digits: Vec<usize> = Vec::new();
     let mut segment_descrip = "root";
     let last = RE.find_iter(path).last();
     ids.push(PathSegment {
         descrip: segment_descrip.to_string(),
-        key: get_key_text(&lookup_key, last.is_none())
+        key: hash_text(&lookup_key, full || last.is_none())
     });
     for item in RE.find_iter(path) {
         segment_descrip = item.as_str();
-        let child_idx: usize = item.as_str().parse().unwrap();
-        if child_idx < 8 {
-            lookup_key = current[child_idx].clone();
-            if let Some(next) = get_node(&lookup_key, &db) {
-                current = next;
-                let is_last = if last.is_some() { item.eq(&last.unwrap()) } else { false };
-                ids.push(PathSegment {
-                    descrip: segment_descrip.to_string(),
-                    key: get_key_text(&lookup_key, is_last)
-                });
-            } else {
-                println!("Not found: {}.", &path[0..item.end()]);
-                return None
-            }
+        let child_idx = match item.as_str().parse::<usize>() {
+            Ok(n) if n < 8 => n,
+            _ => return Err(format!("Bad index {} in path.", item.as_str()))
+        };
+        lookup_key = current[child_idx].clone();
+        if let Some(next) = get_node(&lookup_key, db) {
+            current = next;
+            digits.push(child_idx);
+            let is_last = if let Some(last) = last { item == last } else { false };
+            ids.push(PathSegment {
+                descrip: segment_descrip.to_string(),
+                key: hash_text(&lookup_key, full || is_last)
+            });
         } else {
-            println!("Bad index {} in path.", child_idx);
+            return Err(format!("Not found: {}.", &path[0..item.end()]));
         }
     }
-    Some((ids, lookup_key.clone()))
-}
\ No newline at end of file
+    Ok((ids, lookup_key.clone(), digits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaf_ranges() {
+        assert_eq!(leaf_range(&[], 3), (0, 511));
+        assert_eq!(leaf_range(&[2], 3), (128, 191));
+        assert_eq!(leaf_range(&[2, 7], 3), (184, 191));
+        assert_eq!(leaf_range(&[2, 7, 5], 3), (189, 189));
+        assert_eq!(leaf_range(&[], 22), (0, u64::MAX));
+        assert_eq!(leaf_range(&[1], 22), (1 << 63, u64::MAX));
+        assert_eq!(leaf_range(&[0, 1], 22), (1 << 60, (2 << 60) - 1));
+    }
+
+    #[test]
+    fn full_and_find_commands() {
+        let hash_params = merklespike::make_hash_params();
+        let hash_func = merklespike::make_hash_func(&hash_params);
+        let mut db = merklespike::make_db();
+        let tree = Tree::new(&hash_func, 2, &mut db).unwrap();
+        let mut session = Session {
+            tree,
+            db,
+            hash_func: &hash_func,
+            counts: HashMap::new(),
+            last_path: "root".to_string(),
+            last_leaf: None,
+            full_hashes: false
+        };
+        session.run("full").unwrap();
+        assert!(session.full_hashes);
+        session.run("full off").unwrap();
+        assert!(!session.full_hashes);
+        session.run("full on").unwrap();
+        assert!(session.full_hashes);
+        assert!(session.run("full maybe").is_err());
+
+        // The root, and the children of its children, are found by suffix.
+        let root_hex = session.tree.root.to_hex().to_uppercase();
+        assert_eq!(session.find(&root_hex[root_hex.len() - 6..]),
+                   vec![format!("root = {}", root_hex)]);
+        let empty_hex = get_node(&session.tree.root, &session.db).unwrap()[0].to_hex().to_uppercase();
+        let found = session.find(&empty_hex[empty_hex.len() - 6..]);
+        // All 8 children of the root share one hash, and that node is
+        // searched once.
+        assert_eq!(found.len(), 9);
+        assert!(found[0].starts_with("root: child 0 = "));
+        assert!(found[8].starts_with("root/0 = "));
+        session.run(&format!("find {}", &root_hex[root_hex.len() - 6..].to_lowercase())).unwrap();
+        assert!(session.run("find").is_err());
+        assert!(session.run("find xyz").is_err());
+    }
+
+    #[test]
+    fn counts_set_leaves() {
+        let hash_params = merklespike::make_hash_params();
+        let hash_func = merklespike::make_hash_func(&hash_params);
+        let mut db = merklespike::make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        for i in &[0u64, 7, 64, 511] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+        let mut counts = LeafCounts::new();
+        assert_eq!(count_set_leaves(&tree.root, 3, &db, &mut counts), 4);
+        let node = get_node(&tree.root, &db).unwrap();
+        assert_eq!(count_set_leaves(&node[0], 2, &db, &mut counts), 2);
+        assert_eq!(count_set_leaves(&node[1], 2, &db, &mut counts), 1);
+        assert_eq!(count_set_leaves(&node[3], 2, &db, &mut counts), 0);
+
+        // Counts are cached by hash, so they stay right after an update.
+        tree.update(&El::from(7u64), El::zero(), &mut db).unwrap();
+        assert_eq!(count_set_leaves(&tree.root, 3, &db, &mut counts), 3);
+    }
+}
@@ -1,50 +1,65 @@
 use std::error;
 use std::fmt;
-use std::error::Error;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::error::MerkleError;
 
 /// Hold a set of bits that can be set, unset, and tested by index.
 /// Basically behave like an array of bits.
-#[derive(Debug)]
+///
+/// Bit order matches RevocationList2020: bit 0 is the most significant
+/// (left-most) bit of the first byte, bit 7 the least significant bit of
+/// that byte, bit 8 the most significant bit of the second byte, and so on.
+/// Internally, bits are packed MSB-first into u32 items, so each item is the
+/// big-endian reading of 4 consecutive bytes.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Bitmap {
-    items: Vec<u32>
+    items: Vec<u32>,
+    bit_count: usize
 }
 
 #[derive(Debug, Clone)]
 pub enum BitmapError {
     BadIndex { actual: usize, size: usize },
-    BadJson(String)
+    BadJson(String),
+    SizeMismatch { left: usize, right: usize }
 }
 
 impl fmt::Display for BitmapError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             BitmapError::BadIndex{ actual, size} => {
-                write!(f, "Bad bitmap index {}. Expected 0 <= n < {}.", actual, size)?;
+                write!(f, "Bad bitmap index {}. Expected 0 <= n < {}.", actual, size)
             },
             BitmapError::BadJson(msg) => {
-                write!(f, "Bad JSON. {}", msg)?;
+                write!(f, "Bad JSON. {}", msg)
+            },
+            BitmapError::SizeMismatch{ left, right } => {
+                write!(f, "Bitmap sizes differ ({} vs. {}).", left, right)
             }
         }
-        write!(f, "invalid first item to double")
     }
 }
 
-impl error::Error for BitmapError {
-    fn description(&self) -> &str {
-        match self {
-            BitmapError::BadIndex{actual: _, size: _} => "Bad bitmap index",
-            BitmapError::BadJson(_) => "Bad JSON"
-        }
-    }
-}
+impl error::Error for BitmapError {}
 
 impl Bitmap {
-    pub fn new(bit_count: usize) -> Result<Bitmap, Box<dyn Error>> {
+    pub fn new(bit_count: usize) -> Result<Bitmap, MerkleError> {
         let mut item_count = bit_count / 32;
         if bit_count % 32 > 0 {
             item_count += 1;
         }
-        Ok(Bitmap { items: vec!(0u32; item_count) })
+        Ok(Bitmap { items: vec!(0u32; item_count), bit_count })
+    }
+
+    fn check_index(&self, i: usize) -> Result<(), BitmapError> {
+        if i >= self.bit_count {
+            return Err(BitmapError::BadIndex { actual: i, size: self.bit_count });
+        }
+        Ok(())
     }
 
     fn get_index_and_shift(i: usize) -> (usize, usize) {
@@ -53,7 +68,10 @@ impl Bitmap {
         return (index, shift)
     }
 
+    /// Panics if i >= len(), rather than setting a padding bit that
+    /// count_ones() and iter_ones() would then report.
     pub fn set_bit(&mut self, i: usize) {
+        assert!(i < self.bit_count, "Bad bitmap index {}. Expected 0 <= n < {}.", i, self.bit_count);
         let (index, shift) = Bitmap::get_index_and_shift(i);
         self.items[index] = self.items[index] | (1u32 << shift);
     }
@@ -69,40 +87,304 @@ impl Bitmap {
         (item >> shift) & 1u32 == 1u32
     }
 
+    /// Like set_bit(), but returns BadIndex instead of panicking or
+    /// touching padding when i is out of range.
+    pub fn try_set_bit(&mut self, i: usize) -> Result<(), BitmapError> {
+        self.check_index(i)?;
+        self.set_bit(i);
+        Ok(())
+    }
+
+    /// Like unset_bit(), but bounds-checked.
+    pub fn try_unset_bit(&mut self, i: usize) -> Result<(), BitmapError> {
+        self.check_index(i)?;
+        self.unset_bit(i);
+        Ok(())
+    }
+
+    /// Like get_bit(), but bounds-checked.
+    pub fn try_get_bit(&self, i: usize) -> Result<bool, BitmapError> {
+        self.check_index(i)?;
+        Ok(self.get_bit(i))
+    }
+
     pub fn get_byte_for_bit(&self, i: usize) -> u8 {
         let (index, shift) = Bitmap::get_index_and_shift(i);
         let item = &self.items[index];
         ((item >> ((shift / 8) * 8)) as u8) & 0xFFu8
     }
 
-    #[cfg(__notyet__)]
-    pub fn from_revlist2020<R>(reader: R) -> Result<Bitmap, Box<dyn Error>>
+    /// Read a RevocationList2020 credential and decode its
+    /// credentialSubject.encodedList (base64url of a gzipped bitstring).
+    pub fn from_revlist2020<R>(reader: R) -> Result<Bitmap, MerkleError>
         where R: io::Read {
-        // Serde has a method that will read directly from a reader. However,
-        // its own documentation says this method is less efficient than
-        // reading a full file into a string and then calling ::from_str.
-        // Hence, I'm doing taking more performant path.
-        let mut buffered = BufReader::new(reader);
-        let mut txt = String::new();
-        buffered.read_to_string(&mut txt)?;
-        match txt.find('{') {
-            Some(open_brace_idx) => {
-                let revlist_cred = serde_json::from_str(&txt[open_brace_idx..])?;
-                let context = revlist_cred["@context"];
-                if context.eq(serde_json::Value::Null) {
-                    return Err(Box::from(BitmapError::BadJson("No @context.".to_string())));
+        Bitmap::from_encoded_list(&read_encoded_list(reader)?)
+    }
+
+    /// Decode an encodedList: base64url of a gzipped bitstring.
+    pub fn from_encoded_list(encoded: &str) -> Result<Bitmap, MerkleError> {
+        let compressed = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)?;
+        let mut bytes = Vec::new();
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut bytes)
+            .map_err(MerkleError::Compression)?;
+        Ok(Bitmap::from_bytes(&bytes))
+    }
+
+    /// The inverse of from_encoded_list(): gzip to_bytes() and encode it as
+    /// base64url, ready for credentialSubject.encodedList.
+    pub fn to_encoded_list(&self) -> Result<String, MerkleError> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&self.to_bytes()).map_err(MerkleError::Compression)?;
+        let compressed = encoder.finish().map_err(MerkleError::Compression)?;
+        Ok(base64::encode_config(&compressed, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Build a bitmap of `bytes.len() * 8` bits from big-endian, MSB-first
+    /// bytes (the layout of a decompressed RevocationList2020 encodedList).
+    pub fn from_bytes(bytes: &[u8]) -> Bitmap {
+        let mut items = vec!(0u32; bytes.len().div_ceil(4));
+        for (i, byte) in bytes.iter().enumerate() {
+            items[i / 4] |= (*byte as u32) << (24 - 8 * (i % 4));
+        }
+        Bitmap { items, bit_count: bytes.len() * 8 }
+    }
+
+    /// The inverse of from_bytes(). If len() isn't a multiple of 8, the
+    /// unused low bits of the last byte are zero.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.items.len() * 4);
+        for item in &self.items {
+            bytes.extend_from_slice(&item.to_be_bytes());
+        }
+        bytes.truncate(self.bit_count.div_ceil(8));
+        bytes
+    }
+
+    pub(crate) fn words(&self) -> &[u32] {
+        &self.items
+    }
+
+    pub(crate) fn words_mut(&mut self) -> &mut [u32] {
+        &mut self.items
+    }
+
+    /// The logical number of bits, exactly as passed to new(). Storage is
+    /// rounded up to a multiple of 32, but the padding isn't addressable.
+    pub fn len(&self) -> usize {
+        self.bit_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bit_count == 0
+    }
+
+    /// How many bits are set (population count).
+    pub fn count_ones(&self) -> usize {
+        self.items.iter().map(|item| item.count_ones() as usize).sum()
+    }
+
+    /// The index of the nth (counting from 0) unset bit, if there are that
+    /// many. Whole words are counted at once, so this is fast enough to pick
+    /// a random free index in a large list.
+    pub fn nth_zero(&self, n: usize) -> Option<usize> {
+        let mut n = n;
+        for (w, item) in self.items.iter().enumerate() {
+            let start = w * 32;
+            let bits_here = std::cmp::min(32, self.bit_count - start);
+            // Bits past bit_count are always zero, so aren't counted here.
+            let zeros = bits_here - item.count_ones() as usize;
+            if n < zeros {
+                return (start..start + bits_here).filter(|i| !self.get_bit(*i)).nth(n);
+            }
+            n -= zeros;
+        }
+        None
+    }
+
+    /// Iterate over the indexes of all set bits, in ascending order. Words
+    /// that are entirely zero are skipped without testing individual bits.
+    pub fn iter_ones(&self) -> SetBits<'_> {
+        SetBits { items: &self.items, index: 0, current: 0 }
+    }
+
+    fn check_same_size(&self, other: &Bitmap) -> Result<(), BitmapError> {
+        if self.bit_count != other.bit_count {
+            return Err(BitmapError::SizeMismatch { left: self.len(), right: other.len() });
+        }
+        Ok(())
+    }
+
+    fn combine_with<F>(&mut self, other: &Bitmap, f: F) -> Result<(), BitmapError>
+        where F: Fn(u32, u32) -> u32 {
+        self.check_same_size(other)?;
+        for (mine, theirs) in self.items.iter_mut().zip(other.items.iter()) {
+            *mine = f(*mine, *theirs);
+        }
+        Ok(())
+    }
+
+    /// Keep only the bits that are also set in `other`.
+    pub fn and_with(&mut self, other: &Bitmap) -> Result<(), BitmapError> {
+        self.combine_with(other, |a, b| a & b)
+    }
+
+    /// Set every bit that is set in `other`.
+    pub fn or_with(&mut self, other: &Bitmap) -> Result<(), BitmapError> {
+        self.combine_with(other, |a, b| a | b)
+    }
+
+    /// Flip every bit that is set in `other`.
+    pub fn xor_with(&mut self, other: &Bitmap) -> Result<(), BitmapError> {
+        self.combine_with(other, |a, b| a ^ b)
+    }
+
+    /// Clear every bit that is set in `other`.
+    pub fn and_not_with(&mut self, other: &Bitmap) -> Result<(), BitmapError> {
+        self.combine_with(other, |a, b| a & !b)
+    }
+
+    /// Compare this bitmap (the older version of a status list) to a newer
+    /// one, and report which indexes were set and which were cleared.
+    pub fn diff(&self, newer: &Bitmap) -> Result<BitmapDiff, BitmapError> {
+        self.check_same_size(newer)?;
+        let mut d = BitmapDiff { newly_set: Vec::new(), newly_cleared: Vec::new() };
+        for (index, (old, new)) in self.items.iter().zip(newer.items.iter()).enumerate() {
+            let set = new & !old;
+            let cleared = old & !new;
+            push_ones(index, set, &mut d.newly_set);
+            push_ones(index, cleared, &mut d.newly_cleared);
+        }
+        Ok(d)
+    }
+}
+
+/// Read a RevocationList2020 credential and return its
+/// credentialSubject.encodedList, still encoded.
+pub(crate) fn read_encoded_list<R>(reader: R) -> Result<String, MerkleError>
+    where R: io::Read {
+    // Serde has a method that will read directly from a reader. However,
+    // its own documentation says this method is less efficient than
+    // reading a full file into a string and then calling ::from_str.
+    // Hence, I'm doing taking more performant path.
+    let mut buffered = BufReader::new(reader);
+    let mut txt = String::new();
+    buffered.read_to_string(&mut txt)?;
+    match txt.find('{') {
+        Some(open_brace_idx) => {
+            let revlist_cred: serde_json::Value = serde_json::from_str(&txt[open_brace_idx..])?;
+            if revlist_cred["@context"].is_null() {
+                return Err(BitmapError::BadJson("No @context.".to_string()).into());
+            }
+            match revlist_cred["credentialSubject"]["encodedList"].as_str() {
+                Some(encoded) => Ok(encoded.to_string()),
+                None => {
+                    Err(BitmapError::BadJson("No credentialSubject.encodedList.".to_string()).into())
                 }
-                if context.eq(serde_json::Value::Null) {}
-                Bitmap::new(25)
-            },
-            None => {
-                return Err(Box::from(BitmapError::BadJson("No open brace in supposed JSON text.".to_string())));
             }
+        },
+        None => {
+            Err(BitmapError::BadJson("No open brace in supposed JSON text.".to_string()).into())
         }
     }
+}
 
-    pub fn len(&self) -> usize {
-        32 * self.items.len()
+/// The operations shared by the dense Bitmap and the CompressedBitmap, so
+/// code that walks a revocation list (such as the tree builders) can accept
+/// either representation.
+pub trait BitSet {
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// May panic if i >= len().
+    fn get_bit(&self, i: usize) -> bool;
+    fn set_bit(&mut self, i: usize);
+    fn unset_bit(&mut self, i: usize);
+    fn count_ones(&self) -> usize;
+    /// Index of the first set bit at or after `from`, if any. Implementations
+    /// should skip runs of zeros without testing each bit.
+    fn next_one(&self, from: usize) -> Option<usize>;
+}
+
+impl BitSet for Bitmap {
+    fn len(&self) -> usize {
+        Bitmap::len(self)
+    }
+
+    fn get_bit(&self, i: usize) -> bool {
+        Bitmap::get_bit(self, i)
+    }
+
+    fn set_bit(&mut self, i: usize) {
+        Bitmap::set_bit(self, i)
+    }
+
+    fn unset_bit(&mut self, i: usize) {
+        Bitmap::unset_bit(self, i)
+    }
+
+    fn count_ones(&self) -> usize {
+        Bitmap::count_ones(self)
+    }
+
+    fn next_one(&self, from: usize) -> Option<usize> {
+        if from >= self.bit_count {
+            return None;
+        }
+        let (mut index, shift) = Bitmap::get_index_and_shift(from);
+        // Ignore bits before `from` in the first item.
+        let mut item = self.items[index] & (!0u32 >> (31 - shift));
+        loop {
+            if item != 0 {
+                let i = index * 32 + item.leading_zeros() as usize;
+                return if i < self.bit_count { Some(i) } else { None };
+            }
+            index += 1;
+            if index >= self.items.len() {
+                return None;
+            }
+            item = self.items[index];
+        }
+    }
+}
+
+/// The indexes that changed between two versions of a bitmap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitmapDiff {
+    pub newly_set: Vec<usize>,
+    pub newly_cleared: Vec<usize>
+}
+
+fn push_ones(index: usize, mut item: u32, out: &mut Vec<usize>) {
+    // Bit 0 of the bitmap is the most significant bit of the item.
+    while item != 0 {
+        let lz = item.leading_zeros() as usize;
+        out.push(index * 32 + lz);
+        item &= !(1u32 << (31 - lz));
+    }
+}
+
+/// Iterator over the indexes of set bits in a Bitmap.
+pub struct SetBits<'a> {
+    items: &'a [u32],
+    index: usize,
+    current: u32
+}
+
+impl<'a> Iterator for SetBits<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.current == 0 {
+            if self.index >= self.items.len() {
+                return None;
+            }
+            self.current = self.items[self.index];
+            self.index += 1;
+        }
+        let lz = self.current.leading_zeros() as usize;
+        self.current &= !(1u32 << (31 - lz));
+        Some((self.index - 1) * 32 + lz)
     }
 }
 
@@ -117,6 +399,14 @@ mod tests {
         assert_eq!(b.get_bit(255), false);
     }
 
+    #[test]
+    fn error_display() {
+        let e = BitmapError::BadIndex { actual: 25, size: 25 };
+        assert_eq!(e.to_string(), "Bad bitmap index 25. Expected 0 <= n < 25.");
+        let e = BitmapError::BadJson("No @context.".to_string());
+        assert_eq!(e.to_string(), "Bad JSON. No @context.");
+    }
+
     #[test]
     fn ctor_misaligned() {
         let b = Bitmap::new(25).unwrap();
@@ -124,6 +414,39 @@ mod tests {
         assert_eq!(b.get_bit(25), false);
     }
 
+    #[test]
+    fn exact_len() {
+        assert_eq!(Bitmap::new(25).unwrap().len(), 25);
+        assert_eq!(Bitmap::new(256).unwrap().len(), 256);
+        assert_eq!(Bitmap::new(0).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn bounds_checked() {
+        let mut b = Bitmap::new(25).unwrap();
+        b.try_set_bit(24).unwrap();
+        assert!(b.try_get_bit(24).unwrap());
+        match b.try_set_bit(25) {
+            Err(BitmapError::BadIndex { actual, size }) => {
+                assert_eq!(actual, 25);
+                assert_eq!(size, 25);
+            },
+            _ => panic!("Expected BadIndex.")
+        }
+        assert!(b.try_get_bit(1000).is_err());
+        assert!(b.try_unset_bit(25).is_err());
+        b.try_unset_bit(24).unwrap();
+        assert_eq!(b.count_ones(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bad bitmap index 25")]
+    fn set_bit_rejects_padding() {
+        let mut b = Bitmap::new(25).unwrap();
+        assert!(!b.is_empty());
+        b.set_bit(25);
+    }
+
     #[test]
     fn one_set() {
         let mut b = Bitmap::new(256).unwrap();
@@ -142,19 +465,113 @@ mod tests {
     }
 
     #[test]
-    #[cfg(__notyet__)]
+    fn count_and_iterate() {
+        let mut b = Bitmap::new(256).unwrap();
+        for i in &[3, 31, 32, 200, 255] {
+            b.set_bit(*i);
+        }
+        assert_eq!(b.count_ones(), 5);
+        assert_eq!(b.iter_ones().collect::<Vec<usize>>(), vec![3, 31, 32, 200, 255]);
+        assert_eq!(Bitmap::new(256).unwrap().iter_ones().next(), None);
+    }
+
+    #[test]
+    fn bulk_ops() {
+        let mut a = Bitmap::new(64).unwrap();
+        let mut b = Bitmap::new(64).unwrap();
+        a.set_bit(1);
+        a.set_bit(40);
+        b.set_bit(40);
+        b.set_bit(63);
+
+        let mut x = Bitmap::new(64).unwrap();
+        x.or_with(&a).unwrap();
+        x.and_with(&b).unwrap();
+        assert_eq!(x.iter_ones().collect::<Vec<usize>>(), vec![40]);
+
+        x.or_with(&a).unwrap();
+        x.xor_with(&b).unwrap();
+        assert_eq!(x.iter_ones().collect::<Vec<usize>>(), vec![1, 63]);
+
+        x.and_not_with(&a).unwrap();
+        assert_eq!(x.iter_ones().collect::<Vec<usize>>(), vec![63]);
+
+        assert!(x.or_with(&Bitmap::new(256).unwrap()).is_err());
+    }
+
+    #[test]
+    fn diff_versions() {
+        let mut old = Bitmap::new(96).unwrap();
+        old.set_bit(5);
+        old.set_bit(70);
+        let mut new = Bitmap::new(96).unwrap();
+        new.set_bit(5);
+        new.set_bit(33);
+        new.set_bit(95);
+        let d = old.diff(&new).unwrap();
+        assert_eq!(d.newly_set, vec![33, 95]);
+        assert_eq!(d.newly_cleared, vec![70]);
+    }
+
+    #[test]
+    fn next_one_skips_zeros() {
+        let mut b = Bitmap::new(1000).unwrap();
+        b.set_bit(7);
+        b.set_bit(900);
+        assert_eq!(BitSet::next_one(&b, 0), Some(7));
+        assert_eq!(BitSet::next_one(&b, 7), Some(7));
+        assert_eq!(BitSet::next_one(&b, 8), Some(900));
+        assert_eq!(BitSet::next_one(&b, 901), None);
+        assert_eq!(BitSet::next_one(&b, 5000), None);
+    }
+
+    #[test]
+    fn from_bytes_known_vectors() {
+        let b = Bitmap::from_bytes(&[0x80]);
+        assert_eq!(b.len(), 8);
+        assert_eq!(b.iter_ones().collect::<Vec<usize>>(), vec![0]);
+
+        let b = Bitmap::from_bytes(&[0x00, 0x01]);
+        assert_eq!(b.iter_ones().collect::<Vec<usize>>(), vec![15]);
+
+        let b = Bitmap::from_bytes(&[0xA5, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(b.len(), 40);
+        assert_eq!(b.iter_ones().collect::<Vec<usize>>(), vec![0, 2, 5, 7, 39]);
+    }
+
+    #[test]
+    fn to_bytes_known_vectors() {
+        let mut b = Bitmap::new(12).unwrap();
+        b.set_bit(0);
+        b.set_bit(9);
+        assert_eq!(b.to_bytes(), vec![0x80, 0x40]);
+
+        let mut b = Bitmap::new(64).unwrap();
+        b.set_bit(31);
+        b.set_bit(32);
+        b.set_bit(63);
+        assert_eq!(b.to_bytes(), vec![0, 0, 0, 0x01, 0x80, 0, 0, 0x01]);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let bytes: Vec<u8> = (0..=255u8).collect();
+        let b = Bitmap::from_bytes(&bytes);
+        assert_eq!(b.count_ones(), 8 * 128);
+        assert_eq!(b.to_bytes(), bytes);
+    }
+
+    #[test]
     fn load_bad_revlist_not_json() {
         assert!(Bitmap::from_revlist2020("not json".as_bytes()).is_err());
     }
 
     #[test]
-    #[cfg(__notyet__)]
     fn load_bad_revlist_empty() {
         assert!(Bitmap::from_revlist2020("".as_bytes()).is_err());
     }
 
 
-    #[cfg(__notyet__)]
     const SAMPLE: &str = r#"\
 {
   "@context": [
@@ -170,11 +587,10 @@ mod tests {
     "type": "RevocationList2020",
     "encodedList": "H4sIAAAAAAAAA-3BMQEAAADCoPVPbQsvoAAAAAAAAAAAAAAAAP4GcwM92tQwAAA"
   },
-  "proof": { ... }
+  "proof": {}
 }"#;
 
     #[test]
-    #[cfg(__notyet__)]
     fn load_bad_revlist_no_context() {
         match Bitmap::from_revlist2020(SAMPLE.replace("@", "~").as_bytes()) {
             Ok(_) => panic!("Expected complaint about missing context."),
@@ -185,8 +601,47 @@ mod tests {
     }
 
     #[test]
-    #[cfg(__notyet__)]
+    fn load_revlist_with_revocations() {
+        // Generated independently: a 16 KB list with bits 0, 7, 42, 1000 and
+        // 131071 set, gzipped and base64url-encoded.
+        let encoded = "H4sIAAAAAAACA-3BMQEAIBAAobOJUd_m2sIFOD27PyYAAAAAAAAAAAAAAAAAAACg1gXzUVFiAEAAAA";
+        let cred = SAMPLE.replace(
+            "H4sIAAAAAAAAA-3BMQEAAADCoPVPbQsvoAAAAAAAAAAAAAAAAP4GcwM92tQwAAA", encoded);
+        let b = Bitmap::from_revlist2020(cred.as_bytes()).unwrap();
+        assert_eq!(b.len(), 131_072);
+        assert_eq!(b.iter_ones().collect::<Vec<usize>>(), vec![0, 7, 42, 1000, 131_071]);
+    }
+
+    #[test]
+    fn encoded_list_round_trip() {
+        let mut b = Bitmap::new(131_072).unwrap();
+        for i in &[0usize, 7, 42, 1000, 131_071] {
+            b.set_bit(*i);
+        }
+        let encoded = b.to_encoded_list().unwrap();
+        let cred = SAMPLE.replace(
+            "H4sIAAAAAAAAA-3BMQEAAADCoPVPbQsvoAAAAAAAAAAAAAAAAP4GcwM92tQwAAA", &encoded);
+        let decoded = Bitmap::from_revlist2020(cred.as_bytes()).unwrap();
+        assert_eq!(decoded.to_bytes(), b.to_bytes());
+    }
+
+    #[test]
+    fn nth_zero_skips_set_bits() {
+        let mut b = Bitmap::new(70).unwrap();
+        for i in 0..40 {
+            b.set_bit(i);
+        }
+        b.set_bit(41);
+        assert_eq!(b.nth_zero(0), Some(40));
+        assert_eq!(b.nth_zero(1), Some(42));
+        assert_eq!(b.nth_zero(28), Some(69));
+        assert_eq!(b.nth_zero(29), None);
+    }
+
+    #[test]
     fn load_valid_revlist() {
         let b = Bitmap::from_revlist2020(SAMPLE.as_bytes()).unwrap();
+        assert_eq!(b.len(), 100_000);
+        assert_eq!(b.count_ones(), 0);
     }
 }
@@ -0,0 +1,425 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use crate::bitmap::{Bitmap, BitmapError, BitSet};
+use crate::error::MerkleError;
+
+/// Each chunk covers 2^16 bits, addressed by the high bits of an index.
+const CHUNK_BITS: usize = 1 << 16;
+const CHUNK_WORDS: usize = CHUNK_BITS / 32;
+/// A sorted array of u16 offsets is smaller than a dense chunk (8 KB) until
+/// it holds more than this many entries.
+const ARRAY_MAX: usize = 4096;
+/// A dense chunk only goes back to an array once it's down to this many
+/// entries, so toggling a bit near ARRAY_MAX doesn't convert every time.
+const ARRAY_MIN: usize = ARRAY_MAX / 2;
+
+/// The contents of one 2^16-bit chunk. Sparse chunks store the offsets of
+/// their set bits; crowded chunks store words laid out exactly like Bitmap
+/// (MSB-first), so converting between the two is a copy.
+#[derive(Debug, Clone)]
+enum Container {
+    Array(Vec<u16>),
+    Dense { words: Vec<u32>, ones: usize }
+}
+
+impl Container {
+    fn get(&self, offset: usize) -> bool {
+        match self {
+            Container::Array(offsets) => offsets.binary_search(&(offset as u16)).is_ok(),
+            Container::Dense { words, .. } => {
+                (words[offset / 32] >> (31 - offset % 32)) & 1 == 1
+            }
+        }
+    }
+
+    fn count_ones(&self) -> usize {
+        match self {
+            Container::Array(offsets) => offsets.len(),
+            Container::Dense { ones, .. } => *ones
+        }
+    }
+
+    fn set(&mut self, offset: usize) {
+        match self {
+            Container::Array(offsets) => {
+                if let Err(pos) = offsets.binary_search(&(offset as u16)) {
+                    offsets.insert(pos, offset as u16);
+                }
+                if offsets.len() > ARRAY_MAX {
+                    *self = Container::dense_from_offsets(offsets);
+                }
+            },
+            Container::Dense { words, ones } => {
+                let mask = 1u32 << (31 - offset % 32);
+                if words[offset / 32] & mask == 0 {
+                    words[offset / 32] |= mask;
+                    *ones += 1;
+                }
+            }
+        }
+    }
+
+    fn unset(&mut self, offset: usize) {
+        match self {
+            Container::Array(offsets) => {
+                if let Ok(pos) = offsets.binary_search(&(offset as u16)) {
+                    offsets.remove(pos);
+                }
+            },
+            Container::Dense { words, ones } => {
+                let mask = 1u32 << (31 - offset % 32);
+                if words[offset / 32] & mask != 0 {
+                    words[offset / 32] &= !mask;
+                    *ones -= 1;
+                }
+                if *ones <= ARRAY_MIN {
+                    *self = Container::array_from_words(words);
+                }
+            }
+        }
+    }
+
+    fn next_one(&self, from: usize) -> Option<usize> {
+        match self {
+            Container::Array(offsets) => {
+                let pos = match offsets.binary_search(&(from as u16)) {
+                    Ok(pos) => pos,
+                    Err(pos) => pos
+                };
+                offsets.get(pos).map(|offset| *offset as usize)
+            },
+            Container::Dense { words, .. } => {
+                let mut index = from / 32;
+                let mut word = words[index] & (!0u32 >> (from % 32));
+                loop {
+                    if word != 0 {
+                        return Some(index * 32 + word.leading_zeros() as usize);
+                    }
+                    index += 1;
+                    if index >= words.len() {
+                        return None;
+                    }
+                    word = words[index];
+                }
+            }
+        }
+    }
+
+    fn dense_from_offsets(offsets: &[u16]) -> Container {
+        let mut words = vec!(0u32; CHUNK_WORDS);
+        for offset in offsets {
+            let offset = *offset as usize;
+            words[offset / 32] |= 1u32 << (31 - offset % 32);
+        }
+        Container::Dense { words, ones: offsets.len() }
+    }
+
+    fn array_from_words(words: &[u32]) -> Container {
+        let mut offsets = Vec::new();
+        for (index, word) in words.iter().enumerate() {
+            let mut word = *word;
+            while word != 0 {
+                let lz = word.leading_zeros() as usize;
+                offsets.push((index * 32 + lz) as u16);
+                word &= !(1u32 << (31 - lz));
+            }
+        }
+        Container::Array(offsets)
+    }
+
+    /// Copy a chunk's worth of Bitmap words, choosing whichever form is
+    /// smaller. Returns None if no bits are set.
+    fn from_words(words: &[u32]) -> Option<Container> {
+        let ones: usize = words.iter().map(|w| w.count_ones() as usize).sum();
+        if ones == 0 {
+            None
+        } else if ones <= ARRAY_MAX {
+            Some(Container::array_from_words(words))
+        } else {
+            let mut dense = words.to_vec();
+            dense.resize(CHUNK_WORDS, 0);
+            Some(Container::Dense { words: dense, ones })
+        }
+    }
+}
+
+/// A roaring-style bitmap for huge, sparse revocation lists. Bits are
+/// grouped into chunks of 2^16; chunks with no set bits take no memory,
+/// chunks with few set bits store a sorted list of offsets, and only
+/// crowded chunks pay for a dense array of words. Behaves like Bitmap
+/// (see the BitSet trait), and converts to and from it.
+#[derive(Debug, Clone)]
+pub struct CompressedBitmap {
+    chunks: BTreeMap<usize, Container>,
+    bit_count: usize
+}
+
+impl CompressedBitmap {
+    pub fn new(bit_count: usize) -> Result<CompressedBitmap, MerkleError> {
+        Ok(CompressedBitmap { chunks: BTreeMap::new(), bit_count })
+    }
+
+    pub fn from_bitmap(b: &Bitmap) -> CompressedBitmap {
+        let mut chunks = BTreeMap::new();
+        for (key, words) in b.words().chunks(CHUNK_WORDS).enumerate() {
+            if let Some(container) = Container::from_words(words) {
+                chunks.insert(key, container);
+            }
+        }
+        CompressedBitmap { chunks, bit_count: b.len() }
+    }
+
+    pub fn to_bitmap(&self) -> Result<Bitmap, MerkleError> {
+        let mut b = Bitmap::new(self.bit_count)?;
+        let words = b.words_mut();
+        for (key, container) in &self.chunks {
+            let base = key * CHUNK_WORDS;
+            match container {
+                Container::Array(offsets) => {
+                    for offset in offsets {
+                        let offset = *offset as usize;
+                        words[base + offset / 32] |= 1u32 << (31 - offset % 32);
+                    }
+                },
+                Container::Dense { words: dense, .. } => {
+                    let end = std::cmp::min(words.len(), base + CHUNK_WORDS);
+                    words[base..end].copy_from_slice(&dense[..end - base]);
+                }
+            }
+        }
+        Ok(b)
+    }
+
+    pub fn from_revlist2020<R>(reader: R) -> Result<CompressedBitmap, MerkleError>
+        where R: io::Read {
+        CompressedBitmap::from_encoded_list(&crate::bitmap::read_encoded_list(reader)?)
+    }
+
+    /// Decode an encodedList (base64url of a gzipped bitstring) one chunk
+    /// at a time, so only one chunk's worth of inflated bytes is ever held.
+    pub fn from_encoded_list(encoded: &str) -> Result<CompressedBitmap, MerkleError> {
+        let compressed = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)?;
+        let mut decoder = GzDecoder::new(compressed.as_slice());
+        let mut chunks = BTreeMap::new();
+        let mut bit_count = 0;
+        let mut bytes = Vec::with_capacity(CHUNK_BITS / 8);
+        loop {
+            bytes.clear();
+            (&mut decoder).take((CHUNK_BITS / 8) as u64).read_to_end(&mut bytes)
+                .map_err(MerkleError::Compression)?;
+            if bytes.is_empty() {
+                break;
+            }
+            // Same layout as Bitmap::from_bytes().
+            let words: Vec<u32> = bytes.chunks(4).map(|b| {
+                let mut word = [0u8; 4];
+                word[..b.len()].copy_from_slice(b);
+                u32::from_be_bytes(word)
+            }).collect();
+            if let Some(container) = Container::from_words(&words) {
+                chunks.insert(bit_count / CHUNK_BITS, container);
+            }
+            bit_count += bytes.len() * 8;
+            if bytes.len() < CHUNK_BITS / 8 {
+                break;
+            }
+        }
+        Ok(CompressedBitmap { chunks, bit_count })
+    }
+
+    fn check_index(&self, i: usize) -> Result<(), BitmapError> {
+        if i >= self.bit_count {
+            return Err(BitmapError::BadIndex { actual: i, size: self.bit_count });
+        }
+        Ok(())
+    }
+
+    /// Panics if i >= len(), rather than creating a chunk past the end.
+    pub fn set_bit(&mut self, i: usize) {
+        assert!(i < self.bit_count, "Bad bitmap index {}. Expected 0 <= n < {}.", i, self.bit_count);
+        self.chunks.entry(i / CHUNK_BITS)
+            .or_insert_with(|| Container::Array(Vec::new()))
+            .set(i % CHUNK_BITS);
+    }
+
+    pub fn unset_bit(&mut self, i: usize) {
+        let key = i / CHUNK_BITS;
+        let now_empty = match self.chunks.get_mut(&key) {
+            Some(container) => {
+                container.unset(i % CHUNK_BITS);
+                container.count_ones() == 0
+            },
+            None => false
+        };
+        if now_empty {
+            self.chunks.remove(&key);
+        }
+    }
+
+    pub fn get_bit(&self, i: usize) -> bool {
+        match self.chunks.get(&(i / CHUNK_BITS)) {
+            Some(container) => container.get(i % CHUNK_BITS),
+            None => false
+        }
+    }
+
+    pub fn try_set_bit(&mut self, i: usize) -> Result<(), BitmapError> {
+        self.check_index(i)?;
+        self.set_bit(i);
+        Ok(())
+    }
+
+    pub fn try_unset_bit(&mut self, i: usize) -> Result<(), BitmapError> {
+        self.check_index(i)?;
+        self.unset_bit(i);
+        Ok(())
+    }
+
+    pub fn try_get_bit(&self, i: usize) -> Result<bool, BitmapError> {
+        self.check_index(i)?;
+        Ok(self.get_bit(i))
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.chunks.values().map(|c| c.count_ones()).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bit_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bit_count == 0
+    }
+}
+
+impl BitSet for CompressedBitmap {
+    fn len(&self) -> usize {
+        CompressedBitmap::len(self)
+    }
+
+    fn get_bit(&self, i: usize) -> bool {
+        CompressedBitmap::get_bit(self, i)
+    }
+
+    fn set_bit(&mut self, i: usize) {
+        CompressedBitmap::set_bit(self, i)
+    }
+
+    fn unset_bit(&mut self, i: usize) {
+        CompressedBitmap::unset_bit(self, i)
+    }
+
+    fn count_ones(&self) -> usize {
+        CompressedBitmap::count_ones(self)
+    }
+
+    fn next_one(&self, from: usize) -> Option<usize> {
+        if from >= self.bit_count {
+            return None;
+        }
+        let key = from / CHUNK_BITS;
+        for (k, container) in self.chunks.range(key..) {
+            let start = if *k == key { from % CHUNK_BITS } else { 0 };
+            if let Some(offset) = container.next_one(start) {
+                return Some(k * CHUNK_BITS + offset);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_get_unset() {
+        let mut b = CompressedBitmap::new(8usize.pow(10)).unwrap();
+        b.set_bit(0);
+        b.set_bit(70_000);
+        b.set_bit(1_000_000_000);
+        assert!(b.get_bit(70_000));
+        assert!(!b.get_bit(70_001));
+        assert_eq!(b.count_ones(), 3);
+        b.unset_bit(70_000);
+        assert!(!b.get_bit(70_000));
+        assert_eq!(b.count_ones(), 2);
+        assert_eq!(b.chunks.len(), 2);
+        assert!(b.try_set_bit(8usize.pow(10)).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Bad bitmap index 100")]
+    fn set_bit_past_end() {
+        let mut b = CompressedBitmap::new(100).unwrap();
+        assert!(!b.is_empty());
+        b.set_bit(100);
+    }
+
+    #[test]
+    fn promote_and_demote_container() {
+        let mut b = CompressedBitmap::new(CHUNK_BITS).unwrap();
+        for i in 0..ARRAY_MAX + 1 {
+            b.set_bit(i * 2);
+        }
+        match b.chunks[&0] {
+            Container::Dense { ones, .. } => assert_eq!(ones, ARRAY_MAX + 1),
+            _ => panic!("Expected a dense container.")
+        }
+        // Dropping back to ARRAY_MAX isn't enough to convert again...
+        b.unset_bit(0);
+        assert!(matches!(b.chunks[&0], Container::Dense { .. }));
+        b.set_bit(0);
+        assert!(matches!(b.chunks[&0], Container::Dense { .. }));
+        // ...but ARRAY_MIN is.
+        for i in 0..ARRAY_MAX + 1 - ARRAY_MIN {
+            b.unset_bit(i * 2);
+        }
+        match &b.chunks[&0] {
+            Container::Array(offsets) => assert_eq!(offsets.len(), ARRAY_MIN),
+            _ => panic!("Expected an array container.")
+        }
+        assert!(b.get_bit(2 * ARRAY_MAX));
+        assert!(!b.get_bit(2));
+        assert!(!b.get_bit(3));
+    }
+
+    #[test]
+    fn next_one_across_chunks() {
+        let mut b = CompressedBitmap::new(4 * CHUNK_BITS).unwrap();
+        b.set_bit(5);
+        b.set_bit(3 * CHUNK_BITS + 17);
+        assert_eq!(b.next_one(0), Some(5));
+        assert_eq!(b.next_one(6), Some(3 * CHUNK_BITS + 17));
+        assert_eq!(b.next_one(3 * CHUNK_BITS + 18), None);
+    }
+
+    #[test]
+    fn round_trip_dense() {
+        let mut dense = Bitmap::new(3 * CHUNK_BITS + 100).unwrap();
+        dense.set_bit(1);
+        dense.set_bit(3 * CHUNK_BITS + 99);
+        // Make the middle chunk crowded.
+        for i in 0..ARRAY_MAX * 2 {
+            dense.set_bit(CHUNK_BITS + i);
+        }
+        let c = CompressedBitmap::from_bitmap(&dense);
+        assert_eq!(c.len(), dense.len());
+        assert_eq!(c.count_ones(), dense.count_ones());
+        assert!(c.get_bit(3 * CHUNK_BITS + 99));
+        let back = c.to_bitmap().unwrap();
+        assert_eq!(back.iter_ones().collect::<Vec<usize>>(),
+                   dense.iter_ones().collect::<Vec<usize>>());
+
+        // Decoding pads to whole bytes, like Bitmap::from_encoded_list().
+        let encoded = dense.to_encoded_list().unwrap();
+        let decoded = CompressedBitmap::from_encoded_list(&encoded).unwrap();
+        assert_eq!(decoded.to_bitmap().unwrap(), Bitmap::from_encoded_list(&encoded).unwrap());
+        assert_eq!(decoded.count_ones(), dense.count_ones());
+    }
+}
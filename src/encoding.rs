@@ -0,0 +1,291 @@
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::{Arity8MerkleTreeHash, PoseidonHash8};
+use serde_json::{json, Value};
+
+use crate::{El, MerkleError, EL_BYTES};
+use crate::proof::AuthPath;
+
+/// The current version of the binary proof format.
+pub const PROOF_FORMAT_VERSION: u8 = 1;
+
+/// Bit in the first level's mask that says the leaf value is present.
+const LEAF_PRESENT: u8 = 0x80;
+
+/// Reads big-endian integers and field elements from a byte slice, failing
+/// cleanly if the data runs out.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], MerkleError> {
+        if self.pos + n > self.bytes.len() {
+            return Err(MerkleError::Encoding("Data is truncated.".to_string()));
+        }
+        let b = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, MerkleError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, MerkleError> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(b))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, MerkleError> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    pub(crate) fn el(&mut self) -> Result<El, MerkleError> {
+        El::from_bytes(self.take(EL_BYTES)?)
+            .map_err(|e| MerkleError::Encoding(format!("Bad field element. {:?}", e)))
+    }
+
+    /// How many bytes haven't been read yet. Check a decoded count against
+    /// this before allocating room for that many items.
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    /// Fail if anything is left over.
+    pub(crate) fn finish(&self) -> Result<(), MerkleError> {
+        if self.pos != self.bytes.len() {
+            return Err(MerkleError::Encoding(format!(
+                "{} unexpected bytes at end of data.", self.bytes.len() - self.pos)));
+        }
+        Ok(())
+    }
+}
+
+/// Identify the hash parameters a proof was made with: the last 8 bytes of
+/// the hash of the inputs 1 through 8. Proofs made with different Poseidon
+/// params (or sbox) can't verify against each other, so the codec refuses
+/// to decode them rather than produce a path that silently fails.
+pub fn params_fingerprint(hash_func: &PoseidonHash8) -> Result<[u8; 8], MerkleError> {
+    let inputs = (1..=8u64).map(El::from).collect();
+    let bytes = hash_func.hash(inputs)?.to_bytes();
+    let mut fingerprint = [0u8; 8];
+    fingerprint.copy_from_slice(&bytes[bytes.len() - 8..]);
+    Ok(fingerprint)
+}
+
+/// Encodes and decodes auth paths for one set of hash params.
+///
+/// Binary format, version 1 (integers are big-endian):
+///
+/// ```text
+/// version      1 byte
+/// fingerprint  8 bytes (see params_fingerprint)
+/// depth        1 byte
+/// leaf index   8 bytes
+/// then, for each level from the leaves up:
+///   mask       1 byte; bit i (LSB first) set if sibling i follows
+///   [leaf]     EL_BYTES, on the first level only, if bit 7 of its mask is set
+///   siblings   EL_BYTES each, for the bits set in the mask
+/// ```
+///
+/// Siblings equal to the empty-subtree hash for their level, and a zero
+/// leaf, are left out, so proofs in sparse trees are a fraction of the
+/// 7 x depth field elements of a naive encoding.
+pub struct ProofCodec {
+    fingerprint: [u8; 8],
+    /// empty_hashes[h] is the root of an empty subtree of height h.
+    empty_hashes: Vec<El>
+}
+
+impl ProofCodec {
+    /// Prepare to handle paths in trees up to `max_depth` deep.
+    pub fn new(hash_func: &PoseidonHash8, max_depth: usize) -> Result<ProofCodec, MerkleError> {
+        Ok(ProofCodec {
+            fingerprint: params_fingerprint(hash_func)?,
+            empty_hashes: crate::empty_subtree_hashes(hash_func, max_depth)?
+        })
+    }
+
+    pub fn fingerprint(&self) -> [u8; 8] {
+        self.fingerprint
+    }
+
+    fn check_depth(&self, depth: usize) -> Result<(), MerkleError> {
+        if depth >= self.empty_hashes.len() || depth > u8::MAX as usize {
+            return Err(MerkleError::Encoding(format!(
+                "Depth {} is more than this codec handles ({}).", depth, self.empty_hashes.len() - 1)));
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, path: &AuthPath) -> Result<Vec<u8>, MerkleError> {
+        let depth = path.depth();
+        self.check_depth(depth)?;
+        let mut out = Vec::with_capacity(18 + depth + EL_BYTES);
+        out.push(PROOF_FORMAT_VERSION);
+        out.extend_from_slice(&self.fingerprint);
+        out.push(depth as u8);
+        out.extend_from_slice(&path.index.to_be_bytes());
+        for (height, siblings) in path.siblings.iter().enumerate() {
+            let mut mask = 0u8;
+            for (i, s) in siblings.iter().enumerate() {
+                if *s != self.empty_hashes[height] {
+                    mask |= 1 << i;
+                }
+            }
+            let leaf_present = height == 0 && !path.leaf.is_zero();
+            if leaf_present {
+                mask |= LEAF_PRESENT;
+            }
+            out.push(mask);
+            if leaf_present {
+                out.extend_from_slice(&path.leaf.to_bytes());
+            }
+            for (i, s) in siblings.iter().enumerate() {
+                if mask & (1 << i) != 0 {
+                    out.extend_from_slice(&s.to_bytes());
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<AuthPath, MerkleError> {
+        let mut r = Reader::new(bytes);
+        let version = r.u8()?;
+        if version != PROOF_FORMAT_VERSION {
+            return Err(MerkleError::Encoding(format!("Unsupported proof version {}.", version)));
+        }
+        if r.take(8)? != self.fingerprint {
+            return Err(MerkleError::Encoding("Proof was made with different hash params.".to_string()));
+        }
+        let depth = r.u8()? as usize;
+        self.check_depth(depth)?;
+        let index = r.u64()?;
+        if 8u64.checked_pow(depth as u32).is_some_and(|capacity| index >= capacity) {
+            return Err(MerkleError::Encoding(format!(
+                "Leaf {} is beyond a tree of depth {}.", index, depth)));
+        }
+        let mut leaf = El::zero();
+        let mut siblings = Vec::with_capacity(depth);
+        for height in 0..depth {
+            let mask = r.u8()?;
+            if mask & LEAF_PRESENT != 0 {
+                if height > 0 {
+                    return Err(MerkleError::Encoding(format!("Bad mask at level {}.", height)));
+                }
+                leaf = r.el()?;
+            }
+            let mut level = Vec::with_capacity(7);
+            for i in 0..7 {
+                level.push(if mask & (1 << i) != 0 { r.el()? } else { self.empty_hashes[height].clone() });
+            }
+            // 7 is past the end, so nothing is skipped.
+            siblings.push(crate::proof::siblings_of(&level, 7));
+        }
+        r.finish()?;
+        Ok(AuthPath { index, leaf, siblings })
+    }
+
+    /// Wrap the binary encoding (base64url, without padding) in a JSON
+    /// object that also shows the version, depth and index.
+    pub fn encode_json(&self, path: &AuthPath) -> Result<Value, MerkleError> {
+        let bytes = self.encode(path)?;
+        Ok(json!({
+            "type": "MerkleAuthPath8",
+            "version": PROOF_FORMAT_VERSION,
+            "fingerprint": hex::encode(self.fingerprint),
+            "depth": path.depth(),
+            "index": path.index,
+            "proof": base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+        }))
+    }
+
+    /// Decode the output of encode_json(). Only "proof" is needed; the
+    /// other fields, if present, must agree with it.
+    pub fn decode_json(&self, v: &Value) -> Result<AuthPath, MerkleError> {
+        let encoded = v["proof"].as_str()
+            .ok_or_else(|| MerkleError::Encoding("JSON has no \"proof\" string.".to_string()))?;
+        let path = self.decode(&base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)?)?;
+        let agrees = |field: &str, expected: u64| v[field].is_null() || v[field].as_u64() == Some(expected);
+        if !agrees("depth", path.depth() as u64) || !agrees("index", path.index)
+            || !agrees("version", PROOF_FORMAT_VERSION as u64) {
+            return Err(MerkleError::Encoding("JSON fields disagree with the encoded proof.".to_string()));
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_db, make_hash_func, make_hash_params, Tree};
+
+    #[test]
+    fn round_trips_and_elides_empty_siblings() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let depth = 6;
+        let mut tree = Tree::new(&hash_func, depth, &mut db).unwrap();
+        for i in &[5u64, 6, 4096, 200000] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+        let codec = ProofCodec::new(&hash_func, 12).unwrap();
+
+        for i in &[5u64, 7, 262143] {
+            let path = AuthPath::new(&tree, &db, *i).unwrap();
+            let bytes = codec.encode(&path).unwrap();
+            assert_eq!(codec.decode(&bytes).unwrap(), path);
+            assert!(bytes.len() < 7 * depth * EL_BYTES / 4);
+            let json = codec.encode_json(&path).unwrap();
+            assert_eq!(json["index"], *i);
+            assert_eq!(codec.decode_json(&json).unwrap(), path);
+        }
+
+        // Leaf 5: a set leaf, one set sibling (6), and the non-empty
+        // subtrees /0/1 (holding 4096) and /6 (holding 200000).
+        let bytes = codec.encode(&AuthPath::new(&tree, &db, 5).unwrap()).unwrap();
+        assert_eq!(bytes.len(), 18 + depth + 4 * EL_BYTES);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        let codec = ProofCodec::new(&hash_func, 3).unwrap();
+        let bytes = codec.encode(&AuthPath::new(&tree, &db, 9).unwrap()).unwrap();
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = 2;
+        assert!(codec.decode(&wrong_version).is_err());
+        let mut wrong_params = bytes.clone();
+        wrong_params[3] ^= 1;
+        assert!(codec.decode(&wrong_params).is_err());
+        assert!(codec.decode(&bytes[..bytes.len() - 1]).is_err());
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(codec.decode(&extra).is_err());
+
+        let mut json = codec.encode_json(&AuthPath::new(&tree, &db, 9).unwrap()).unwrap();
+        json["index"] = json!(10);
+        assert!(codec.decode_json(&json).is_err());
+
+        // Past depth 21 every u64 is a valid index; a short proof still fails cleanly.
+        let deep_codec = ProofCodec::new(&hash_func, 25).unwrap();
+        let mut deep = vec![PROOF_FORMAT_VERSION];
+        deep.extend_from_slice(&deep_codec.fingerprint());
+        deep.push(22);
+        deep.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(deep_codec.decode(&deep), Err(MerkleError::Encoding(_))));
+    }
+}
@@ -0,0 +1,125 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use bulletproofs_amcl::errors::{BulletproofError, R1CSError};
+
+use crate::bitmap::BitmapError;
+
+/// Everything that can go wrong in this crate, wrapping the errors of the
+/// libraries we build on so callers can use `?` throughout.
+#[derive(Debug)]
+pub enum MerkleError {
+    Bitmap(BitmapError),
+    Json(serde_json::Error),
+    Base64(base64::DecodeError),
+    /// Reading or writing gzip/zip data failed.
+    Compression(io::Error),
+    Io(io::Error),
+    /// Poseidon hashing (or some other constraint-system operation) failed.
+    Hash(R1CSError),
+    /// A node was missing from the hash db, or the db couldn't be saved or loaded.
+    Db(String),
+    /// A bitmap has more bits than a tree of the given depth has leaves.
+    TooManyBits { bit_count: usize, depth: usize },
+    /// A witness and an update to it describe different trees.
+    Witness(String),
+    /// Serialized data (a proof or bundle) is malformed or from an
+    /// incompatible version or set of hash params.
+    Encoding(String),
+    /// A registry operation doesn't make sense for the credential's state.
+    Registry(String),
+    /// A credential index that hasn't been issued (or is past the end).
+    NotIssued(u64),
+    /// An op log is damaged, or replaying it doesn't reproduce its roots.
+    Log(String)
+}
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MerkleError::Bitmap(e) => write!(f, "{}", e),
+            MerkleError::Json(e) => write!(f, "Bad JSON. {}", e),
+            MerkleError::Base64(e) => write!(f, "Bad base64. {}", e),
+            MerkleError::Compression(e) => write!(f, "Compression error. {}", e),
+            MerkleError::Io(e) => write!(f, "I/O error. {}", e),
+            MerkleError::Hash(e) => write!(f, "Hash error. {:?}", e),
+            MerkleError::Db(msg) => write!(f, "Hash db error. {}", msg),
+            MerkleError::TooManyBits { bit_count, depth } => {
+                write!(f, "Bitmap of {} bits doesn't fit in a tree of depth {}.", bit_count, depth)
+            },
+            MerkleError::Witness(msg) => write!(f, "Witness error. {}", msg),
+            MerkleError::Encoding(msg) => write!(f, "Encoding error. {}", msg),
+            MerkleError::Registry(msg) => write!(f, "Registry error. {}", msg),
+            MerkleError::NotIssued(index) => write!(f, "Registry error. Credential {} hasn't been issued.", index),
+            MerkleError::Log(msg) => write!(f, "Op log error. {}", msg)
+        }
+    }
+}
+
+impl error::Error for MerkleError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MerkleError::Bitmap(e) => Some(e),
+            MerkleError::Json(e) => Some(e),
+            MerkleError::Base64(e) => Some(e),
+            MerkleError::Compression(e) => Some(e),
+            MerkleError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<BitmapError> for MerkleError {
+    fn from(e: BitmapError) -> Self {
+        MerkleError::Bitmap(e)
+    }
+}
+
+impl From<serde_json::Error> for MerkleError {
+    fn from(e: serde_json::Error) -> Self {
+        MerkleError::Json(e)
+    }
+}
+
+impl From<base64::DecodeError> for MerkleError {
+    fn from(e: base64::DecodeError) -> Self {
+        MerkleError::Base64(e)
+    }
+}
+
+impl From<io::Error> for MerkleError {
+    fn from(e: io::Error) -> Self {
+        MerkleError::Io(e)
+    }
+}
+
+impl From<R1CSError> for MerkleError {
+    fn from(e: R1CSError) -> Self {
+        MerkleError::Hash(e)
+    }
+}
+
+impl From<BulletproofError> for MerkleError {
+    fn from(e: BulletproofError) -> Self {
+        MerkleError::Db(format!("{:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_has_no_leftovers() {
+        let e = MerkleError::from(BitmapError::BadIndex { actual: 9, size: 8 });
+        assert_eq!(e.to_string(), "Bad bitmap index 9. Expected 0 <= n < 8.");
+    }
+
+    #[test]
+    fn source_is_wrapped_error() {
+        use std::error::Error;
+        let e = MerkleError::from(io::Error::other("boom"));
+        assert_eq!(e.source().unwrap().to_string(), "boom");
+    }
+}
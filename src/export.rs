@@ -0,0 +1,191 @@
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+use bulletproofs_amcl::utils::hash_db::HashDb;
+use serde_json::{json, Value};
+
+use crate::{Db, El, MerkleError};
+use crate::proof::digits_to_path;
+
+/// What we know about one node in an exported subtree.
+#[derive(Debug, Clone)]
+pub enum NodeKind {
+    /// An interior node, with all 8 children expanded.
+    Interior(Vec<ExportNode>),
+    /// The default hash of an empty subtree; its children aren't listed.
+    Empty,
+    /// A leaf value.
+    Leaf,
+    /// An interior node below the requested number of levels.
+    Truncated
+}
+
+/// A snapshot of part of a tree, ready to render as DOT or JSON.
+#[derive(Debug, Clone)]
+pub struct ExportNode {
+    /// Child numbers from the root of the whole tree to this node.
+    pub path: Vec<usize>,
+    pub hash: El,
+    /// How many levels this node sits above the leaves.
+    pub height: usize,
+    pub kind: NodeKind
+}
+
+/// Read the subtree under `key` (reached from the root by following `path`,
+/// and `height` levels above the leaves) out of `db`, expanding at most
+/// `levels` levels. Subtrees that hash to the default empty value for their
+/// height are collapsed into a single Empty node.
+pub fn export_subtree(db: &Db, hash_func: &PoseidonHash8, key: &El, path: &[usize],
+                      height: usize, levels: usize) -> Result<ExportNode, MerkleError> {
+    let empty_hashes = crate::empty_subtree_hashes(hash_func, height)?;
+    export_node(db, &empty_hashes, key, path.to_vec(), height, levels)
+}
+
+fn export_node(db: &Db, empty_hashes: &[El], key: &El, path: Vec<usize>,
+               height: usize, levels: usize) -> Result<ExportNode, MerkleError> {
+    let kind = if height == 0 {
+        NodeKind::Leaf
+    } else if *key == empty_hashes[height] {
+        NodeKind::Empty
+    } else if levels == 0 {
+        NodeKind::Truncated
+    } else {
+        let node = db.get(&key.to_bytes())?;
+        let mut children = Vec::with_capacity(8);
+        for (i, child) in node.iter().enumerate() {
+            let mut child_path = path.clone();
+            child_path.push(i);
+            children.push(export_node(db, empty_hashes, child, child_path, height - 1, levels - 1)?);
+        }
+        NodeKind::Interior(children)
+    };
+    Ok(ExportNode { path, hash: key.clone(), height, kind })
+}
+
+/// How a hash is shown to people, here and in treewalk: "0" for zero,
+/// otherwise the full hex, or its last 8 digits unless `full` is set.
+pub fn hash_text(el: &El, full: bool) -> String {
+    if el.is_zero() {
+        "0".to_string()
+    } else {
+        let hex = el.to_hex();
+        if full {
+            hex
+        } else {
+            format!("...{}", &hex[hex.len() - 8..])
+        }
+    }
+}
+
+impl ExportNode {
+    fn dot_id(&self) -> String {
+        let mut id = "n".to_string();
+        for d in &self.path {
+            id.push('_');
+            id.push_str(&d.to_string());
+        }
+        id
+    }
+
+    /// Render as a Graphviz digraph, labelling each node with its path and
+    /// (shortened) hash.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph subtree {\n");
+        out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+        self.write_dot(&mut out);
+        out.push_str("}\n");
+        out
+    }
+
+    fn write_dot(&self, out: &mut String) {
+        let id = self.dot_id();
+        match &self.kind {
+            NodeKind::Interior(children) => {
+                out.push_str(&format!("  {} [label=\"{}\\n{}\"];\n",
+                                      id, digits_to_path(&self.path), hash_text(&self.hash, false)));
+                for (i, child) in children.iter().enumerate() {
+                    child.write_dot(out);
+                    out.push_str(&format!("  {} -> {} [label=\"{}\"];\n", id, child.dot_id(), i));
+                }
+            },
+            NodeKind::Empty => {
+                out.push_str(&format!("  {} [label=\"empty\\nheight {}\", style=dashed];\n",
+                                      id, self.height));
+            },
+            NodeKind::Leaf => {
+                out.push_str(&format!("  {} [label=\"{}\", shape=ellipse];\n",
+                                      id, hash_text(&self.hash, false)));
+            },
+            NodeKind::Truncated => {
+                out.push_str(&format!("  {} [label=\"{}\\n{}\\n...\", style=dotted];\n",
+                                      id, digits_to_path(&self.path), hash_text(&self.hash, false)));
+            }
+        }
+    }
+
+    /// Render as nested JSON objects with full hashes.
+    pub fn to_json(&self) -> Value {
+        let kind = match &self.kind {
+            NodeKind::Interior(_) => "interior",
+            NodeKind::Empty => "empty",
+            NodeKind::Leaf => "leaf",
+            NodeKind::Truncated => "truncated"
+        };
+        let mut v = json!({
+            "path": digits_to_path(&self.path),
+            "hash": self.hash.to_hex(),
+            "height": self.height,
+            "kind": kind
+        });
+        if let NodeKind::Interior(children) = &self.kind {
+            v["children"] = Value::Array(children.iter().map(|c| c.to_json()).collect());
+        }
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_db, make_hash_func, make_hash_params, Tree};
+
+    #[test]
+    fn formats_hashes() {
+        let hex = El::from(0x1234_5678_9abc_u64).to_hex();
+        assert_eq!(hash_text(&El::zero(), true), "0");
+        assert_eq!(hash_text(&El::from(0x1234_5678_9abc_u64), true), hex);
+        assert_eq!(hash_text(&El::from(0x1234_5678_9abc_u64), false), format!("...{}", &hex[hex.len() - 8..]));
+    }
+
+    #[test]
+    fn collapses_empty_subtrees() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        tree.update(&El::from(65u64), El::one(), &mut db).unwrap();
+
+        let x = export_subtree(&db, &hash_func, &tree.root, &[], 3, 2).unwrap();
+        let children = match &x.kind {
+            NodeKind::Interior(children) => children,
+            _ => panic!("Expected root to be expanded.")
+        };
+        match &children[0].kind {
+            NodeKind::Empty => (),
+            _ => panic!("Expected /0 to be collapsed.")
+        }
+        match &children[1].kind {
+            NodeKind::Interior(grandchildren) => match &grandchildren[0].kind {
+                NodeKind::Truncated => (),
+                _ => panic!("Expected /1/0 to be truncated.")
+            },
+            _ => panic!("Expected /1 to be expanded.")
+        }
+
+        let dot = x.to_dot();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("n_1 -> n_1_0"));
+        let json = x.to_json();
+        assert_eq!(json["children"][1]["children"][0]["path"], "/1/0");
+        assert_eq!(json["children"][2]["kind"], "empty");
+    }
+}
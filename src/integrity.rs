@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::{Arity8MerkleTreeHash, PoseidonHash8};
+use bulletproofs_amcl::utils::hash_db::HashDb;
+
+use crate::{Db, El, MerkleError};
+use crate::proof::digits_to_path;
+
+/// Something wrong with one node of a tree.
+#[derive(Debug, Clone)]
+pub enum Problem {
+    /// A node that a parent refers to isn't in the db.
+    Missing { path: String, key: El },
+    /// A node's children don't hash to its key.
+    Corrupt { path: String, key: El, computed: El }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Missing { path, key } => {
+                write!(f, "{}: missing node {}", path, key.to_hex())
+            },
+            Problem::Corrupt { path, key, computed } => {
+                write!(f, "{}: corrupt node {}; children hash to {}", path, key.to_hex(), computed.to_hex())
+            }
+        }
+    }
+}
+
+/// The result of checking every node reachable from a root.
+#[derive(Debug, Clone)]
+pub struct IntegrityReport {
+    /// Distinct nodes whose hash was recomputed.
+    pub checked: usize,
+    pub problems: Vec<Problem>
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for p in &self.problems {
+            writeln!(f, "{}", p)?;
+        }
+        if self.is_ok() {
+            writeln!(f, "Integrity check passed; {} nodes verified.", self.checked)
+        } else {
+            writeln!(f, "Integrity check FAILED; {} problems in {} nodes.",
+                     self.problems.len(), self.checked)
+        }
+    }
+}
+
+/// Walk every node reachable from `root` in a tree of the given depth,
+/// recompute the Poseidon hash of its 8 children, and confirm that it
+/// matches the node's key. Subtrees shared by several parents are checked
+/// once, and reported under the first path that reaches them. Corrupt nodes
+/// are still descended into, so one bad node doesn't hide others below it.
+pub fn check_integrity(root: &El, depth: usize, db: &Db, hash_func: &PoseidonHash8)
+    -> Result<IntegrityReport, MerkleError> {
+
+    let mut report = IntegrityReport { checked: 0, problems: Vec::new() };
+    let mut visited: HashSet<Vec<u8>> = HashSet::new();
+    let mut stack: Vec<(El, Vec<usize>)> = vec![(root.clone(), Vec::new())];
+    while let Some((key, digits)) = stack.pop() {
+        let key_bytes = key.to_bytes();
+        if !visited.insert(key_bytes.clone()) {
+            continue
+        }
+        let children = match db.get(&key_bytes) {
+            Ok(children) => children,
+            Err(_) => {
+                report.problems.push(Problem::Missing { path: digits_to_path(&digits), key });
+                continue
+            }
+        };
+        report.checked += 1;
+        let computed = hash_func.hash(children.to_vec())?;
+        if computed != key {
+            report.problems.push(Problem::Corrupt { path: digits_to_path(&digits), key, computed });
+        }
+        // Children of the bottom level of interior nodes are leaf values,
+        // which aren't db entries.
+        if digits.len() + 1 < depth {
+            for (i, child) in children.iter().enumerate().rev() {
+                let mut child_digits = digits.clone();
+                child_digits.push(i);
+                stack.push((child.clone(), child_digits));
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_db, make_hash_func, make_hash_params, Tree};
+
+    #[test]
+    fn finds_corrupt_and_missing_nodes() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        tree.update(&El::from(9u64), El::one(), &mut db).unwrap();
+
+        let report = check_integrity(&tree.root, 3, &db, &hash_func).unwrap();
+        assert!(report.is_ok());
+        // root, /0, /0/1, and the shared empty subtree of height 2 and 1.
+        assert_eq!(report.checked, 5);
+
+        // Change a leaf under /0/1 without rehashing.
+        let root_children = db.get(&tree.root.to_bytes()).unwrap();
+        let n0 = db.get(&root_children[0].to_bytes()).unwrap();
+        let mut n01 = db.get(&n0[1].to_bytes()).unwrap();
+        n01[2] = El::one();
+        db.insert(n0[1].to_bytes(), n01);
+        let report = check_integrity(&tree.root, 3, &db, &hash_func).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        match &report.problems[0] {
+            Problem::Corrupt { path, .. } => assert_eq!(path, "/0/1"),
+            _ => panic!("Expected a corrupt node.")
+        }
+
+        // A db holding only the root.
+        let mut partial = make_db();
+        partial.insert(tree.root.to_bytes(), root_children);
+        let report = check_integrity(&tree.root, 3, &partial, &hash_func).unwrap();
+        assert!(!report.is_ok());
+        match &report.problems[0] {
+            Problem::Missing { path, .. } => assert_eq!(path, "/0"),
+            _ => panic!("Expected a missing node.")
+        }
+    }
+}
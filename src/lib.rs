@@ -9,10 +9,26 @@ use bulletproofs_amcl::{
     utils::hash_db::InMemoryHashDb
 };
 use amcl_wrapper::field_elem::FieldElement;
-use std::io;
-use std::io::Write;
-
-mod bitmap;
+use std::path::Path;
+
+pub mod allocator;
+pub mod bitmap;
+pub mod compressed_bitmap;
+pub mod encoding;
+pub mod error;
+pub mod export;
+pub mod integrity;
+pub mod memory;
+pub mod multiproof;
+pub mod oplog;
+pub mod proof;
+pub mod registry;
+pub mod sharding;
+pub mod statement;
+pub mod stats;
+pub mod witness;
+
+pub use error::MerkleError;
 
 extern crate jemalloc_ctl;
 extern crate jemallocator;
@@ -45,15 +61,28 @@ pub fn get_allocated_memory() -> usize {
     get_net_allocated_memory(0)
 }
 
+/// Print how much memory is allocated beyond `base_value`, and return it.
+#[deprecated(note = "printing between measurements skews them; record checkpoints with memory::MemRecorder \
+                     and print its report at the end")]
 pub fn memdump(milestone: &str, base_value: usize) -> usize {
     let a = get_net_allocated_memory(base_value);
     println!("At {}, using {} of memory.", milestone, &byte_count_to_friendly(a));
     a
 }
 
+/// Fill a tree of `depth` to `fill_ratio`, then time saving, loading,
+/// proofs and other ways of building the same tree. Memory is sampled at
+/// checkpoints by a MemRecorder, and nothing is printed until the end, so
+/// formatting and stdout don't skew the timings or the memory figures.
 pub fn experiment(depth: usize, fill_ratio: f64) {
+    use std::fmt::Write;
 
-    let start_allocated = memdump("start of experiment", 0);
+    // Reserve room for one checkpoint per 100 inserts, plus milestones, so
+    // that recording doesn't allocate while we're measuring.
+    let expected_inserts = 8u64.pow(depth as u32) as f64 * fill_ratio;
+    let mut recorder = memory::MemRecorder::new(expected_inserts as usize / 100 + 16);
+    let mut report = String::with_capacity(16 * 1024);
+    recorder.checkpoint("start of experiment");
 
     let mut db = make_db();
 
@@ -63,16 +92,16 @@ pub fn experiment(depth: usize, fill_ratio: f64) {
         params: &hash_params,
         sbox: &SboxType::Quint,
     };
-    let mut tree = VanillaSparseMerkleTree8::new(&hash_func, depth as usize, &mut db).unwrap();
+    let mut tree = VanillaSparseMerkleTree8::new(&hash_func, depth, &mut db).unwrap();
 
     // How many leaf nodes does this tree have?
-    let capacity = (8 as u64).pow(depth as u32);
+    let capacity = 8u64.pow(depth as u32);
     // So, given the desired fill ratio, how many inserts should we do?
     let insert_count = (capacity as f64 * fill_ratio) as u64;
     use rand::distributions::{Distribution, Uniform};
     let dist = Uniform::from(0..capacity);
 
-    println!("Capacity of tree = {}; filling {}% or {}.", capacity, fill_ratio * 100.0, insert_count);
+    writeln!(report, "Capacity of tree = {}; filling {}% or {}.", capacity, fill_ratio * 100.0, insert_count).unwrap();
 
     use std::time::Instant;
     let now = Instant::now();
@@ -80,49 +109,71 @@ pub fn experiment(depth: usize, fill_ratio: f64) {
     for i in 0..insert_count {
         let s = FieldElement::from(dist.sample(&mut rng));
         tree.update(&s, FieldElement::one(), &mut db).unwrap();
-        io::stdout().write_all(b".").ok();
         if i % 100 == 99 {
-            io::stdout().write_all(b"\n").ok();
-            memdump(&format!("{} nodes inserted", i + 1), start_allocated);
+            recorder.checkpoint_n("nodes inserted", i + 1);
         }
-        io::stdout().flush().ok();
     }
 
     let elapsed = now.elapsed().as_millis();
-    println!("\nFill experiment completed after {} milliseconds ({} millis / insert).",
-             elapsed, (elapsed as f64) / (insert_count as f64));
-    println!("{} nodes now in tree.", db.len());
-    memdump("end of fill experiment", start_allocated);
+    recorder.checkpoint("end of fill experiment");
+    writeln!(report, "Fill experiment completed after {} milliseconds ({} millis / insert).",
+             elapsed, (elapsed as f64) / (insert_count as f64)).unwrap();
+    writeln!(report, "{} nodes now in tree.", db.len()).unwrap();
+    match stats::TreeStats::new(&tree, &db, &hash_func) {
+        Ok(s) => write!(report, "{}", s).unwrap(),
+        Err(e) => writeln!(report, "Couldn't audit tree. {}", e).unwrap()
+    }
+
+    for leaf_count in &[10usize, 100] {
+        let indices: Vec<u64> = (0..*leaf_count).map(|_| dist.sample(&mut rng)).collect();
+        let now = Instant::now();
+        let proof = multiproof::MultiProof::new(&tree, &db, &indices).unwrap();
+        let generated = now.elapsed().as_millis();
+        let now = Instant::now();
+        let ok = proof.verify(&hash_func, &tree.root).unwrap();
+        let verified = now.elapsed().as_millis();
+        let independent = proof.independent_element_count();
+        writeln!(report, "Multiproof for {} leaves: {} field elements ({} bytes) vs {} for independent proofs \
+                  ({:.1}% smaller); generated in {} millis, verified ({}) in {} millis.",
+                 proof.leaves.len(), proof.element_count(), proof.byte_count(), independent,
+                 100.0 * (1.0 - proof.element_count() as f64 / independent as f64),
+                 generated, ok, verified).unwrap();
+    }
 
-    use std::path::Path;
     let path = Path::new("/tmp/x.zip");
     let now = Instant::now();
     db.save(path, &tree.root).ok();
     let elapsed = now.elapsed().as_millis();
-    println!("Saved and compressed file in {} millis.", elapsed);
+    recorder.checkpoint("saved db");
+    writeln!(report, "Saved and compressed file in {} millis.", elapsed).unwrap();
 
     use std::fs;
-    let uncompressed_size = 432 * db.len();
+    let uncompressed_size = stats::SERIALIZED_ENTRY_BYTES * db.len();
     let compressed_size = fs::metadata(path).unwrap().len();
     let compression_ratio = 1.0 - (compressed_size as f64 / uncompressed_size as f64);
 
-    println!("Saved hashdb ({} bytes) to compressed file {} ({} bytes; {:.1}% compression).",
-             uncompressed_size, path.display(), compressed_size, compression_ratio * 100.0);
+    writeln!(report, "Saved hashdb ({} bytes) to compressed file {} ({} bytes; {:.1}% compression).",
+             uncompressed_size, path.display(), compressed_size, compression_ratio * 100.0).unwrap();
 
     let mut db2 = Db::new();
     let now = Instant::now();
     let root2 = db2.load(path).unwrap();
     let elapsed = now.elapsed().as_millis();
-    println!("Loading db back from disk took {} millis.", elapsed);
+    recorder.checkpoint("loaded db");
+    writeln!(report, "Loading db back from disk took {} millis.", elapsed).unwrap();
     if root2.eq(&tree.root) {
-        if db2.len() == db.len() {
-            println!("Integrity check passed.");
-        } else {
-            println!("Databases aren't the same size (original={}, reconstituted={}).",
-                db.len(), db2.len());
+        if db2.len() != db.len() {
+            writeln!(report, "Databases aren't the same size (original={}, reconstituted={}).",
+                db.len(), db2.len()).unwrap();
         }
+        let now = Instant::now();
+        match integrity::check_integrity(&root2, depth, &db2, &hash_func) {
+            Ok(r) => write!(report, "{}", r).unwrap(),
+            Err(e) => writeln!(report, "Couldn't check integrity. {}", e).unwrap()
+        }
+        writeln!(report, "Checked integrity in {} millis.", now.elapsed().as_millis()).unwrap();
     } else {
-        println!("Roots changed.");
+        writeln!(report, "Roots changed.").unwrap();
     }
 
     let now = Instant::now();
@@ -131,7 +182,7 @@ pub fn experiment(depth: usize, fill_ratio: f64) {
         revlist.set_bit(dist.sample(&mut rng) as usize);
     }
     let elapsed = now.elapsed().as_millis();
-    println!("Set {} bits in bitmap in {} millis.", insert_count, elapsed);
+    writeln!(report, "Set {} bits in bitmap in {} millis.", insert_count, elapsed).unwrap();
 
     let mut db = make_db();
     let hash_params = make_hash_params();
@@ -140,105 +191,175 @@ pub fn experiment(depth: usize, fill_ratio: f64) {
         sbox: &SboxType::Quint,
     };
     let now = Instant::now();
-    let _tree2 = build_tree_from_bitmap(depth, &revlist, &hash_func, &mut db);
-    println!("Built tree from bitmap in {} millis.", now.elapsed().as_millis());
+    let _tree2 = build_tree_from_bitmap(depth, &revlist, &hash_func, &mut db).unwrap();
+    writeln!(report, "Built tree from bitmap in {} millis.", now.elapsed().as_millis()).unwrap();
+    recorder.checkpoint("built tree from bitmap");
+
+    let compressed = compressed_bitmap::CompressedBitmap::from_bitmap(&revlist);
+    let mut db = make_db();
+    let now = Instant::now();
+    let mut tree3 = build_tree_from_bitmap(depth, &compressed, &hash_func, &mut db).unwrap();
+    writeln!(report, "Built tree from compressed bitmap in {} millis.", now.elapsed().as_millis()).unwrap();
+    recorder.checkpoint("built tree from compressed bitmap");
+
+    // Compare the one deep tree with 64 shards that are two levels shallower
+    // and hold the same leaves.
+    if depth > 2 {
+        let shard_depth = depth - 2;
+        let now = Instant::now();
+        let mut sharded = sharding::ShardedTree::from_bits(&hash_func, 64, shard_depth, &revlist).unwrap();
+        writeln!(report, "Built 64 shards of depth {} from bitmap in {} millis ({} db entries, vs {} for one tree).",
+                 shard_depth, now.elapsed().as_millis(), sharded.db().len(), db.len()).unwrap();
+        recorder.checkpoint("built sharded tree from bitmap");
+
+        let index = dist.sample(&mut rng);
+        let now = Instant::now();
+        tree3.update(&FieldElement::from(index), FieldElement::one(), &mut db).unwrap();
+        let deep_micros = now.elapsed().as_micros();
+        let now = Instant::now();
+        sharded.update(index, FieldElement::one()).unwrap();
+        writeln!(report, "Updating one leaf took {} micros in one tree and {} micros in the shards.",
+                 deep_micros, now.elapsed().as_micros()).unwrap();
+        writeln!(report, "Top commitment {} the one tree's root.",
+                 if sharded.root() == &tree3.root { "matches" } else { "differs from" }).unwrap();
+        let proof = sharded.proof(index).unwrap();
+        writeln!(report, "Proof siblings: {} in one tree; {} within a shard, plus {} in the top tree.",
+                 7 * depth, 7 * proof.local.depth(), 7 * proof.top.depth()).unwrap();
+    }
+    recorder.checkpoint("end of experiment");
+
+    print!("{}", report);
+    println!("\nMemory usage:");
+    print!("{}", recorder);
 }
 
-fn build_tree_from_bitmap<'a>(
-    depth: usize, b: &bitmap::Bitmap,
+/// Build a tree whose leaves are 1 wherever a bit is set in `b`. Only the
+/// set bits and their ancestors are hashed; every run of zeros is covered by
+/// the precomputed empty-subtree hash for its level, so sparse lists (and
+/// especially CompressedBitmaps, whose zero chunks cost nothing to skip)
+/// build in time proportional to the number of revocations.
+pub fn build_tree_from_bitmap<'a, B: bitmap::BitSet>(
+    depth: usize, b: &B,
     hash_func: &'a PoseidonHash8,
-    db: &mut Db) -> Tree<'a> {
+    db: &mut Db) -> Result<Tree<'a>, MerkleError> {
 
     use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::Arity8MerkleTreeHash;
     use bulletproofs_amcl::utils::hash_db::HashDb;
 
+    // The bitmap may be shorter than the tree's capacity (e.g., a status list
+    // of 100,000 entries in a tree of depth 6). Leaves past the end of the
+    // bitmap are vacant.
+    if 8usize.checked_pow(depth as u32).is_some_and(|capacity| b.len() > capacity) {
+        return Err(MerkleError::TooManyBits { bit_count: b.len(), depth });
+    }
+
     // Create a tree of the right depth. This will prepopulate the hash db with the hashes
     // of the 1- and 0-bit leaf nodes, plus all parents of those up to root.
     let mut tree = VanillaSparseMerkleTree8::new(
-        hash_func, depth as usize, db).unwrap();
-
-    //struct PossiblyOwnedFieldElement {
-    //    FieldElement *;
-    //    owned;
-    //}
-    let capacity: usize = 8_u32.pow((depth - 1) as u32) as usize;
-    let mut children_at_prev_level: Vec<FieldElement> = Vec::with_capacity(capacity);
-    // Create the value that represents 1 set bit.
-    let one = FieldElement::one();
-    // Create the most common set of children we're going to see.
-    let all_zeros: DbVal8ary = [
-        FieldElement::zero(),
-        FieldElement::zero(),
-        FieldElement::zero(),
-        FieldElement::zero(),
-        FieldElement::zero(),
-        FieldElement::zero(),
-        FieldElement::zero(),
-        FieldElement::zero(),
-    ];
-    // Figure out what the hash of all zeros is. We'll use this so often that it's
-    // worth caching.
-    let hash_all_zeros = hash_func.hash(all_zeros.to_vec()).unwrap();
-    let mut i = 0;
-    loop {
-        let next8 = b.get_byte_for_bit(i);
-        // If any bits are set...
-        if next8 > 0 {
-            let mut siblings = all_zeros.clone();
-            let mut sibling_index = 0;
-            for j in i..i+8 {
-                if b.get_bit(j) {
-                    siblings[sibling_index] = one.clone();
-                }
-                sibling_index += 1;
+        hash_func, depth, db)?;
+
+    let empty_hashes = empty_subtree_hashes(hash_func, depth)?;
+
+    // Non-empty nodes at the current level, as (index within level, value),
+    // in ascending order of index. Start with the leaves.
+    let mut nodes: Vec<(usize, FieldElement)> = Vec::new();
+    let mut next = b.next_one(0);
+    while let Some(i) = next {
+        nodes.push((i, FieldElement::one()));
+        next = b.next_one(i + 1);
+    }
+
+    for level in 1..=depth {
+        let mut parents: Vec<(usize, FieldElement)> = Vec::with_capacity(nodes.len());
+        let mut k = 0;
+        while k < nodes.len() {
+            let parent_index = nodes[k].0 / 8;
+            let mut siblings: DbVal8ary = [
+                empty_hashes[level - 1].clone(),
+                empty_hashes[level - 1].clone(),
+                empty_hashes[level - 1].clone(),
+                empty_hashes[level - 1].clone(),
+                empty_hashes[level - 1].clone(),
+                empty_hashes[level - 1].clone(),
+                empty_hashes[level - 1].clone(),
+                empty_hashes[level - 1].clone(),
+            ];
+            while k < nodes.len() && nodes[k].0 / 8 == parent_index {
+                siblings[nodes[k].0 % 8] = nodes[k].1.clone();
+                k += 1;
             }
-            let this_hash = hash_func.hash(siblings.to_vec()).unwrap();
-            children_at_prev_level.push(this_hash.clone());
+            let this_hash = hash_func.hash(siblings.to_vec())?;
             let this_hash_bytes = this_hash.to_bytes();
             if !db.contains_key(&this_hash_bytes) {
                 db.insert(this_hash_bytes, siblings);
             }
-        } else {
-            // Nothing to do. All vacant leaf nodes already exist in the sparse tree.
-            children_at_prev_level.push(hash_all_zeros.clone());
-        }
-        i += 8;
-        if i >= b.len() {
-            break;
+            parents.push((parent_index, this_hash));
         }
+        nodes = parents;
     }
+    tree.root = match nodes.pop() {
+        Some((_, root)) => root,
+        None => empty_hashes[depth].clone()
+    };
+    Ok(tree)
+}
 
-    for _level in (2..depth).rev() {
-        let children_at_this_level = children_at_prev_level;
-        children_at_prev_level = Vec::new();
-        let mut i = children_at_this_level.len() - 8;
-        loop {
-            let siblings = &children_at_this_level.as_slice()[i..i+8];
-            let this_hash = hash_func.hash(siblings.to_vec()).unwrap();
-            children_at_prev_level.push(this_hash.clone());
-            let this_hash_bytes = this_hash.to_bytes();
-            if !db.contains_key(&this_hash_bytes) {
-                let array: DbVal8ary = [
-                    siblings[0].clone(),
-                    siblings[1].clone(),
-                    siblings[2].clone(),
-                    siblings[3].clone(),
-                    siblings[4].clone(),
-                    siblings[5].clone(),
-                    siblings[6].clone(),
-                    siblings[7].clone(),
-                ];
-                db.insert(this_hash_bytes, array);
-            }
-            if i == 0 {
-                break;
-            }
-            i -= 8;
-        }
+/// Element n is the hash of an empty subtree of height n (so element 0 is
+/// the value of a vacant leaf, and element `depth` is the root of an empty
+/// tree).
+pub fn empty_subtree_hashes(hash_func: &PoseidonHash8, depth: usize)
+    -> Result<Vec<FieldElement>, MerkleError> {
+
+    use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::Arity8MerkleTreeHash;
+
+    let mut empty_hashes: Vec<FieldElement> = vec![FieldElement::zero()];
+    for level in 1..=depth {
+        let children = vec![empty_hashes[level - 1].clone(); 8];
+        empty_hashes.push(hash_func.hash(children)?);
     }
-    tree.root = hash_func.hash(children_at_prev_level).unwrap();
-    tree
-    //Tree::new_from_precomputed(&hash_func, depth, &root).unwrap()
+    Ok(empty_hashes)
+}
+
+/// Load a hash db snapshot (as written by `Db::save`) into `db`, and return a
+/// tree rooted at the snapshot's root. The snapshot doesn't record depth, so
+/// it's inferred by walking down from the root until we reach the leaves.
+pub fn load_tree<'a>(path: &Path, hash_func: &'a PoseidonHash8, db: &mut Db)
+    -> Result<Tree<'a>, MerkleError> {
+
+    let root = db.load(path).map_err(|e| MerkleError::Db(format!("{:?}", e)))?;
+    let depth = infer_depth(&root, db);
+    if depth == 0 {
+        return Err(MerkleError::Db(format!("Root of {} isn't in the snapshot.", path.display())));
+    }
+    let mut tree = VanillaSparseMerkleTree8::new(hash_func, depth, db)?;
+    tree.root = root;
+    Ok(tree)
+}
+
+/// Count the levels of interior nodes under `root` by following the first
+/// child of each node until we reach something that isn't in the db (a leaf).
+pub fn infer_depth(root: &FieldElement, db: &Db) -> usize {
+    use bulletproofs_amcl::utils::hash_db::HashDb;
+
+    let mut depth = 0;
+    let mut key = root.clone();
+    while let Ok(children) = db.get(&key.to_bytes()) {
+        depth += 1;
+        key = children[0].clone();
+    }
+    depth
+}
+
+/// The depth of the smallest 8-ary tree with at least `bit_count` leaves.
+/// Computed from the bit length, since 8^depth overflows a usize for the
+/// largest counts.
+pub fn tree_depth_for(bit_count: usize) -> usize {
+    if bit_count <= 8 {
+        return 1;
+    }
+    // ceil(log2(bit_count)), then 3 bits per level, rounding up.
+    let bits = (usize::BITS - (bit_count - 1).leading_zeros()) as usize;
+    bits.div_ceil(3)
 }
 
 // ------------------------------------------------------------------
@@ -251,6 +372,8 @@ fn build_tree_from_bitmap<'a>(
 /// The type used to store leaves of the merkle tree.
 pub type Db = InMemoryHashDb::<DbVal8ary>;
 pub type El = FieldElement;
+/// Size of a serialized El (a BLS12-381 field element).
+pub const EL_BYTES: usize = 48;
 
 pub type Tree<'a> = VanillaSparseMerkleTree8<'a, PoseidonHash8<'a>>;
 
@@ -288,3 +411,74 @@ pub fn make_tree(hash_func: &PoseidonHash8, tree_depth: usize, db: &mut Db) -> i
     0
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_from_bitmap_matches_updates() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let depth = 3;
+
+        let mut revlist = bitmap::Bitmap::new(500).unwrap();
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, depth, &mut db).unwrap();
+        for i in &[0u64, 9, 63, 64, 300, 499] {
+            revlist.set_bit(*i as usize);
+            tree.update(&FieldElement::from(*i), FieldElement::one(), &mut db).unwrap();
+        }
+
+        let mut db2 = make_db();
+        let built = build_tree_from_bitmap(depth, &revlist, &hash_func, &mut db2).unwrap();
+        assert_eq!(built.root, tree.root);
+
+        let compressed = compressed_bitmap::CompressedBitmap::from_bitmap(&revlist);
+        let mut db3 = make_db();
+        let built = build_tree_from_bitmap(depth, &compressed, &hash_func, &mut db3).unwrap();
+        assert_eq!(built.root, tree.root);
+    }
+
+    #[test]
+    fn depth_for_bit_counts() {
+        assert_eq!(tree_depth_for(0), 1);
+        assert_eq!(tree_depth_for(8), 1);
+        assert_eq!(tree_depth_for(9), 2);
+        assert_eq!(tree_depth_for(64), 2);
+        assert_eq!(tree_depth_for(65), 3);
+        assert_eq!(tree_depth_for(1 << 63), 21);
+        assert_eq!(tree_depth_for((1 << 63) + 1), 22);
+        assert_eq!(tree_depth_for(usize::MAX), 22);
+    }
+
+    #[test]
+    fn load_tree_infers_depth() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        for depth in 1..=5 {
+            let mut db = make_db();
+            let mut tree = Tree::new(&hash_func, depth, &mut db).unwrap();
+            assert_eq!(infer_depth(&tree.root, &db), depth);
+            tree.update(&FieldElement::from(5u64), FieldElement::one(), &mut db).unwrap();
+
+            let path = std::env::temp_dir().join(format!("load-tree-{}-{}.zip", depth, std::process::id()));
+            db.save(&path, &tree.root).unwrap();
+            let mut loaded_db = make_db();
+            let loaded = load_tree(&path, &hash_func, &mut loaded_db).unwrap();
+            std::fs::remove_file(&path).ok();
+            assert_eq!(loaded.depth, depth);
+            assert_eq!(loaded.root, tree.root);
+        }
+        assert_eq!(infer_depth(&FieldElement::from(7u64), &make_db()), 0);
+    }
+
+    #[test]
+    fn tree_from_bitmap_too_small() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let revlist = bitmap::Bitmap::new(65).unwrap();
+        let mut db = make_db();
+        assert!(build_tree_from_bitmap(2, &revlist, &hash_func, &mut db).is_err());
+    }
+}
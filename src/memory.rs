@@ -0,0 +1,165 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::byte_count_to_friendly;
+
+/// jemalloc's view of the heap at one moment. See the jemalloc man page for
+/// exactly what each statistic counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemStats {
+    /// Bytes handed out to the application.
+    pub allocated: usize,
+    /// Bytes in active pages; a little more than allocated.
+    pub active: usize,
+    /// Bytes jemalloc uses for its own bookkeeping.
+    pub metadata: usize,
+    /// Bytes in physically resident pages.
+    pub resident: usize,
+    /// Bytes in mapped extents.
+    pub mapped: usize,
+    /// Bytes that were freed but kept mapped for reuse instead of being
+    /// returned to the OS.
+    pub retained: usize
+}
+
+impl MemStats {
+    /// Refresh jemalloc's cached statistics and read them. This doesn't
+    /// allocate, so it can be called in the middle of the code being measured.
+    pub fn read() -> MemStats {
+        jemalloc_ctl::epoch::advance().unwrap();
+        MemStats {
+            allocated: jemalloc_ctl::stats::allocated::read().unwrap(),
+            active: jemalloc_ctl::stats::active::read().unwrap(),
+            metadata: jemalloc_ctl::stats::metadata::read().unwrap(),
+            resident: jemalloc_ctl::stats::resident::read().unwrap(),
+            mapped: jemalloc_ctl::stats::mapped::read().unwrap(),
+            retained: jemalloc_ctl::stats::retained::read().unwrap()
+        }
+    }
+}
+
+/// The statistics captured at one checkpoint.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub label: &'static str,
+    /// Optional progress count (e.g., number of inserts so far).
+    pub count: Option<u64>,
+    /// Time since the recorder was created.
+    pub elapsed: Duration,
+    pub stats: MemStats
+}
+
+/// Collects memory snapshots at checkpoints chosen by the caller, without
+/// printing anything until asked. Labels are static strings so that taking a
+/// checkpoint doesn't itself allocate (as long as the buffer has room), and
+/// so that measurements aren't skewed by formatting or stdout.
+///
+/// jemalloc doesn't track a high-water mark unless it's built with
+/// profiling, so "peak" means the largest value seen at any checkpoint.
+/// Checkpoint often enough to catch the spikes you care about.
+pub struct MemRecorder {
+    start: Instant,
+    baseline: MemStats,
+    checkpoints: Vec<Checkpoint>
+}
+
+impl MemRecorder {
+    /// Start recording, with room for `capacity` checkpoints before the
+    /// buffer has to grow.
+    pub fn new(capacity: usize) -> MemRecorder {
+        let checkpoints = Vec::with_capacity(capacity);
+        MemRecorder { start: Instant::now(), baseline: MemStats::read(), checkpoints }
+    }
+
+    pub fn checkpoint(&mut self, label: &'static str) {
+        self.record(label, None);
+    }
+
+    pub fn checkpoint_n(&mut self, label: &'static str, count: u64) {
+        self.record(label, Some(count));
+    }
+
+    fn record(&mut self, label: &'static str, count: Option<u64>) {
+        let stats = MemStats::read();
+        let elapsed = self.start.elapsed();
+        self.checkpoints.push(Checkpoint { label, count, elapsed, stats });
+    }
+
+    /// The stats read when the recorder was created.
+    pub fn baseline(&self) -> &MemStats {
+        &self.baseline
+    }
+
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    fn peak<F: Fn(&MemStats) -> usize>(&self, f: F) -> usize {
+        self.checkpoints.iter().map(|c| f(&c.stats)).max().unwrap_or(0)
+    }
+
+    /// Most bytes allocated at any checkpoint, net of the baseline.
+    pub fn peak_allocated(&self) -> usize {
+        self.peak(|s| s.allocated).saturating_sub(self.baseline.allocated)
+    }
+
+    /// Most resident bytes at any checkpoint, net of the baseline.
+    pub fn peak_resident(&self) -> usize {
+        self.peak(|s| s.resident).saturating_sub(self.baseline.resident)
+    }
+
+    /// Most retained bytes at any checkpoint, net of the baseline.
+    pub fn peak_retained(&self) -> usize {
+        self.peak(|s| s.retained).saturating_sub(self.baseline.retained)
+    }
+}
+
+impl fmt::Display for MemRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Every column is growth since the baseline (0 if a statistic
+        // shrank), so the numbers in a row can be compared.
+        writeln!(f, "{:<36} {:>10} {:>12} {:>12} {:>12} {:>12}",
+                 "checkpoint", "millis", "+allocated", "+resident", "+retained", "+metadata")?;
+        let b = &self.baseline;
+        for c in &self.checkpoints {
+            let label = match c.count {
+                Some(n) => format!("{} {}", n, c.label),
+                None => c.label.to_string()
+            };
+            let s = &c.stats;
+            writeln!(f, "{:<36} {:>10} {:>12} {:>12} {:>12} {:>12}",
+                     label, c.elapsed.as_millis(),
+                     byte_count_to_friendly(s.allocated.saturating_sub(b.allocated)),
+                     byte_count_to_friendly(s.resident.saturating_sub(b.resident)),
+                     byte_count_to_friendly(s.retained.saturating_sub(b.retained)),
+                     byte_count_to_friendly(s.metadata.saturating_sub(b.metadata)))?;
+        }
+        writeln!(f, "Peaks above baseline: allocated {}, resident {}, retained {}.",
+                 byte_count_to_friendly(self.peak_allocated()),
+                 byte_count_to_friendly(self.peak_resident()),
+                 byte_count_to_friendly(self.peak_retained()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_in_order() {
+        let mut recorder = MemRecorder::new(4);
+        recorder.checkpoint("start");
+        let v: Vec<u8> = vec![1; 1 << 20];
+        recorder.checkpoint_n("bytes filled", v.len() as u64);
+        drop(v);
+        recorder.checkpoint("end");
+
+        let labels: Vec<&str> = recorder.checkpoints().iter().map(|c| c.label).collect();
+        assert_eq!(labels, vec!["start", "bytes filled", "end"]);
+        assert_eq!(recorder.checkpoints()[1].count, Some(1 << 20));
+        assert!(recorder.checkpoints()[2].elapsed >= recorder.checkpoints()[0].elapsed);
+        let peak = recorder.checkpoints().iter().map(|c| c.stats.allocated).max().unwrap();
+        assert_eq!(recorder.peak_allocated(), peak.saturating_sub(recorder.baseline().allocated));
+        assert!(format!("{}", recorder).contains("1048576 bytes filled"));
+    }
+}
@@ -0,0 +1,184 @@
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::{Arity8MerkleTreeHash, PoseidonHash8};
+use bulletproofs_amcl::utils::hash_db::HashDb;
+
+use crate::{Db, El, MerkleError, Tree, EL_BYTES};
+
+/// Proof of several leaves at once. Where paths overlap, a node that one
+/// path needs as a sibling is often on another path, so the verifier can
+/// compute it; only the siblings nobody can compute are included, each once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiProof {
+    pub depth: usize,
+    /// (index, value) for each leaf being proved, in index order.
+    pub leaves: Vec<(u64, El)>,
+    /// The siblings that aren't on any path, from the leaf level up, and in
+    /// position order within each level.
+    pub siblings: Vec<El>
+}
+
+impl MultiProof {
+    /// Gather the leaves at `indices` (duplicates are ignored) and the
+    /// siblings needed to prove them against `tree.root`.
+    pub fn new(tree: &Tree, db: &Db, indices: &[u64]) -> Result<MultiProof, MerkleError> {
+        let depth = tree.depth;
+        let mut indices = indices.to_vec();
+        indices.sort();
+        indices.dedup();
+        if let Some(last) = indices.last() {
+            if 8u64.checked_pow(depth as u32).is_some_and(|capacity| *last >= capacity) {
+                return Err(MerkleError::Witness(format!(
+                    "Leaf {} is beyond a tree of depth {}.", last, depth)));
+            }
+        }
+
+        // Siblings by height, collected from the root down.
+        let mut by_height: Vec<Vec<El>> = vec![Vec::new(); depth];
+        // Nodes on some path at the current level, as (position, key).
+        let mut frontier = if indices.is_empty() { Vec::new() } else { vec![(0u64, tree.root.clone())] };
+        for level in 0..depth {
+            let height = depth - level - 1;
+            let mut wanted: Vec<u64> = indices.iter().map(|i| i >> (3 * height as u32)).collect();
+            wanted.dedup();
+            let mut wanted = wanted.into_iter().peekable();
+            let mut next = Vec::with_capacity(frontier.len());
+            for (position, key) in frontier {
+                let children = db.get(&key.to_bytes())?;
+                for (i, child) in children.iter().enumerate() {
+                    let child_position = position * 8 + i as u64;
+                    if wanted.peek() == Some(&child_position) {
+                        next.push((child_position, child.clone()));
+                        wanted.next();
+                    } else {
+                        by_height[height].push(child.clone());
+                    }
+                }
+            }
+            frontier = next;
+        }
+        let leaves = frontier;
+        let siblings = by_height.into_iter().flatten().collect();
+        Ok(MultiProof { depth, leaves, siblings })
+    }
+
+    /// Hash from the leaves up, filling in each level's missing children
+    /// from `siblings`. The leaves must be in strictly ascending index
+    /// order and inside the tree, as new() makes them.
+    pub fn compute_root(&self, hash_func: &PoseidonHash8) -> Result<El, MerkleError> {
+        if self.leaves.is_empty() {
+            return Err(MerkleError::Witness("Multiproof has no leaves.".to_string()));
+        }
+        if self.leaves.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err(MerkleError::Witness("Multiproof leaves aren't sorted and unique.".to_string()));
+        }
+        let last = self.leaves[self.leaves.len() - 1].0;
+        if 8u64.checked_pow(self.depth as u32).is_some_and(|capacity| last >= capacity) {
+            return Err(MerkleError::Witness(format!(
+                "Leaf {} is beyond a tree of depth {}.", last, self.depth)));
+        }
+        let too_few = || MerkleError::Witness("Multiproof has too few siblings.".to_string());
+        let mut siblings = self.siblings.iter();
+        let mut current: Vec<(u64, El)> = self.leaves.clone();
+        for _ in 0..self.depth {
+            let mut parents: Vec<(u64, El)> = Vec::with_capacity(current.len());
+            let mut known = current.into_iter().peekable();
+            while let Some((first, _)) = known.peek() {
+                let parent = first / 8;
+                let mut children = Vec::with_capacity(8);
+                for i in 0..8 {
+                    match known.peek() {
+                        Some((p, _)) if *p == parent * 8 + i => children.push(known.next().unwrap().1),
+                        _ => children.push(siblings.next().ok_or_else(too_few)?.clone())
+                    }
+                }
+                parents.push((parent, hash_func.hash(children)?));
+            }
+            current = parents;
+        }
+        if siblings.next().is_some() {
+            return Err(MerkleError::Witness("Multiproof has too many siblings.".to_string()));
+        }
+        match current.pop() {
+            Some((0, root)) if current.is_empty() => Ok(root),
+            _ => Err(MerkleError::Witness("Multiproof leaves don't meet at a single root.".to_string()))
+        }
+    }
+
+    pub fn verify(&self, hash_func: &PoseidonHash8, root: &El) -> Result<bool, MerkleError> {
+        Ok(self.compute_root(hash_func)? == *root)
+    }
+
+    /// Field elements in this proof (leaf values and siblings).
+    pub fn element_count(&self) -> usize {
+        self.leaves.len() + self.siblings.len()
+    }
+
+    /// Field elements in one independent auth path per leaf.
+    pub fn independent_element_count(&self) -> usize {
+        self.leaves.len() * (1 + 7 * self.depth)
+    }
+
+    /// Rough serialized size: field elements plus an 8-byte index per leaf.
+    pub fn byte_count(&self) -> usize {
+        self.element_count() * EL_BYTES + self.leaves.len() * 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_db, make_hash_func, make_hash_params};
+    use crate::proof::AuthPath;
+
+    #[test]
+    fn shares_siblings_and_verifies() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        for i in &[3u64, 64, 300, 301] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+
+        let proof = MultiProof::new(&tree, &db, &[301, 3, 300, 7, 3]).unwrap();
+        assert_eq!(proof.leaves.iter().map(|l| l.0).collect::<Vec<u64>>(), vec![3, 7, 300, 301]);
+        assert!(proof.verify(&hash_func, &tree.root).unwrap());
+        // Leaves 3 and 7 share a bottom node (6 siblings), as do 300 and 301;
+        // their parents /0/0 and /4/5 need 7 siblings each, and /0 and /4
+        // need 6 at the top.
+        assert_eq!(proof.siblings.len(), 6 + 6 + 7 + 7 + 6);
+        assert!(proof.element_count() < proof.independent_element_count());
+
+        let single = MultiProof::new(&tree, &db, &[300]).unwrap();
+        let path = AuthPath::new(&tree, &db, 300).unwrap();
+        assert_eq!(single.siblings.len(), 7 * 3);
+        assert_eq!(single.compute_root(&hash_func).unwrap(), path.compute_root(&hash_func).unwrap());
+
+        let mut forged = proof.clone();
+        forged.leaves[1].1 = El::one();
+        assert!(!forged.verify(&hash_func, &tree.root).unwrap());
+        let mut short = proof.clone();
+        short.siblings.pop();
+        assert!(short.compute_root(&hash_func).is_err());
+        assert!(MultiProof::new(&tree, &db, &[512]).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_leaves() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        tree.update(&El::from(300u64), El::one(), &mut db).unwrap();
+        let proof = MultiProof::new(&tree, &db, &[3, 7, 300]).unwrap();
+
+        let mut reordered = proof.clone();
+        reordered.leaves.swap(1, 2);
+        assert!(matches!(reordered.compute_root(&hash_func), Err(MerkleError::Witness(_))));
+        let mut duplicated = proof.clone();
+        duplicated.leaves[1] = duplicated.leaves[0].clone();
+        assert!(matches!(duplicated.compute_root(&hash_func), Err(MerkleError::Witness(_))));
+        let mut out_of_range = MultiProof::new(&tree, &db, &[300]).unwrap();
+        out_of_range.leaves[0].0 += 512;
+        assert!(matches!(out_of_range.compute_root(&hash_func), Err(MerkleError::Witness(_))));
+    }
+}
@@ -0,0 +1,397 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+use flate2::Crc;
+
+use crate::{Db, El, MerkleError, Tree, EL_BYTES};
+use crate::allocator::{IndexAllocator, Strategy, MAX_CAPACITY};
+use crate::bitmap::Bitmap;
+use crate::encoding::Reader;
+
+/// The first 8 bytes of a log: a name and format version.
+const MAGIC: &[u8; 8] = b"MSOPLOG\x01";
+/// Magic, then the registry's capacity.
+const HEADER_BYTES: usize = 8 + 8;
+/// Epoch, op, index, root, checksum.
+const ENTRY_BYTES: usize = 8 + 1 + 8 + EL_BYTES + 4;
+
+/// A change to a registry. Revoke and Reinstate move its root; Issue
+/// leaves the root alone, but is logged so a replay knows which indices
+/// are taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Issue,
+    Revoke,
+    Reinstate
+}
+
+impl Op {
+    fn code(self) -> u8 {
+        match self {
+            Op::Revoke => 1,
+            Op::Reinstate => 2,
+            Op::Issue => 3
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Op> {
+        match code {
+            1 => Some(Op::Revoke),
+            2 => Some(Op::Reinstate),
+            3 => Some(Op::Issue),
+            _ => None
+        }
+    }
+}
+
+/// One operation, and the root it produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    /// 1 for the first operation; epoch 0 is the empty registry.
+    pub epoch: u64,
+    pub op: Op,
+    pub index: u64,
+    pub root: El
+}
+
+impl LogEntry {
+    /// The entry's bytes, without the checksum.
+    fn body(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(ENTRY_BYTES);
+        b.extend_from_slice(&self.epoch.to_be_bytes());
+        b.push(self.op.code());
+        b.extend_from_slice(&self.index.to_be_bytes());
+        b.extend_from_slice(&self.root.to_bytes());
+        b
+    }
+}
+
+/// Each entry's checksum covers the previous entry's checksum as well as its
+/// own bytes, so a missing, reordered or altered entry breaks every later
+/// checksum.
+fn chained_checksum(previous: u32, body: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(&previous.to_be_bytes());
+    crc.update(body);
+    crc.sum()
+}
+
+fn log_error(msg: String) -> MerkleError {
+    MerkleError::Log(msg)
+}
+
+/// Read and check a whole log. Returns the capacity from its header, its
+/// entries, and the length of the valid data; an incomplete entry at the end
+/// (from a crash mid-append) isn't counted.
+fn read_entries(bytes: &[u8]) -> Result<(usize, Vec<LogEntry>, usize), MerkleError> {
+    let mut r = Reader::new(bytes);
+    if bytes.len() < HEADER_BYTES || r.take(8)? != MAGIC {
+        return Err(log_error("Not an op log, or an unsupported version.".to_string()));
+    }
+    let capacity = r.u64()?;
+    if capacity > MAX_CAPACITY as u64 {
+        return Err(log_error(format!("Log header says capacity {}; the most is {}.", capacity, MAX_CAPACITY)));
+    }
+    let capacity = capacity as usize;
+    let mut entries: Vec<LogEntry> = Vec::new();
+    let mut checksum = 0;
+    let complete = (bytes.len() - HEADER_BYTES) / ENTRY_BYTES;
+    for n in 0..complete {
+        let epoch = r.u64()?;
+        let code = r.u8()?;
+        let index = r.u64()?;
+        let root = r.el()?;
+        let stored = r.u32()?;
+        let op = Op::from_code(code)
+            .ok_or_else(|| log_error(format!("Unknown op {} at epoch {}.", code, epoch)))?;
+        let entry = LogEntry { epoch, op, index, root };
+        checksum = chained_checksum(checksum, &entry.body());
+        if stored != checksum {
+            return Err(log_error(format!("Bad checksum in entry {} (epoch {}).", n + 1, epoch)));
+        }
+        if epoch != n as u64 + 1 {
+            return Err(log_error(format!("Entry {} has epoch {}.", n + 1, epoch)));
+        }
+        entries.push(entry);
+    }
+    Ok((capacity, entries, HEADER_BYTES + complete * ENTRY_BYTES))
+}
+
+/// An append-only log of the operations on one registry, each stamped with
+/// an epoch and the resulting root. Entries are flushed to disk before
+/// append() returns.
+pub struct OpLog {
+    file: File,
+    capacity: usize,
+    /// The last entry's epoch and root (epoch 0 and None when empty).
+    epoch: u64,
+    root: Option<El>,
+    checksum: u32,
+    /// How many Issue entries the log holds.
+    issued: usize
+}
+
+impl OpLog {
+    /// Start a new log for a registry of the given capacity. Fails if
+    /// `path` exists.
+    pub fn create(path: &Path, capacity: usize) -> Result<OpLog, MerkleError> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&(capacity as u64).to_be_bytes())?;
+        file.sync_data()?;
+        Ok(OpLog { file, capacity, epoch: 0, root: None, checksum: 0, issued: 0 })
+    }
+
+    /// Open an existing log to append to it. An incomplete entry at the end
+    /// is cut off; any other damage is an error.
+    pub fn open(path: &Path) -> Result<OpLog, MerkleError> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (capacity, entries, valid) = read_entries(&bytes)?;
+        if valid < bytes.len() {
+            file.set_len(valid as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        // The last entry's stored checksum seeds the next one.
+        let checksum = if entries.is_empty() {
+            0
+        } else {
+            let mut b = [0u8; 4];
+            b.copy_from_slice(&bytes[valid - 4..valid]);
+            u32::from_be_bytes(b)
+        };
+        let last = entries.last();
+        Ok(OpLog {
+            file,
+            capacity,
+            epoch: last.map(|e| e.epoch).unwrap_or(0),
+            root: last.map(|e| e.root.clone()),
+            checksum,
+            issued: entries.iter().filter(|e| e.op == Op::Issue).count()
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The epoch of the last entry; 0 if there are none.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The root after the last entry, if there is one.
+    pub fn root(&self) -> Option<&El> {
+        self.root.as_ref()
+    }
+
+    /// How many credentials the log records as issued.
+    pub fn issued_count(&self) -> usize {
+        self.issued
+    }
+
+    /// Record an operation and the root it produces. If the write fails,
+    /// whatever part of the entry reached the file is cut off again.
+    pub fn append(&mut self, op: Op, index: u64, root: &El) -> Result<LogEntry, MerkleError> {
+        let entry = LogEntry { epoch: self.epoch + 1, op, index, root: root.clone() };
+        let mut bytes = entry.body();
+        let checksum = chained_checksum(self.checksum, &bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        let previous_len = self.file.seek(SeekFrom::End(0))?;
+        if let Err(e) = self.file.write_all(&bytes).and_then(|_| self.file.sync_data()) {
+            self.file.set_len(previous_len).ok();
+            self.file.seek(SeekFrom::Start(previous_len)).ok();
+            return Err(e.into());
+        }
+        self.epoch = entry.epoch;
+        self.root = Some(entry.root.clone());
+        self.checksum = checksum;
+        if op == Op::Issue {
+            self.issued += 1;
+        }
+        Ok(entry)
+    }
+}
+
+/// What a replay rebuilt.
+pub struct Replayed<'a> {
+    pub tree: Tree<'a>,
+    /// The issued and revoked indices. Hand it to Registry::with_allocator()
+    /// to carry on where the log left off.
+    pub allocator: IndexAllocator,
+    /// The last epoch applied.
+    pub epoch: u64
+}
+
+/// Rebuild a registry's tree (in `db`) and allocator by applying every
+/// entry in the log at `path` to an empty registry, checking the root after
+/// each one. Fails at the first entry whose root doesn't match or whose op
+/// doesn't make sense (such as revoking twice, or before issuing). The
+/// allocator hands out new indices by `strategy`.
+pub fn replay<'a>(path: &Path, hash_func: &'a PoseidonHash8<'a>, db: &mut Db, strategy: Strategy)
+    -> Result<Replayed<'a>, MerkleError> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let (capacity, entries, _) = read_entries(&bytes)?;
+    let mut issued = Bitmap::new(capacity)?;
+    let mut revoked = Bitmap::new(capacity)?;
+    let mut tree = Tree::new(hash_func, crate::tree_depth_for(capacity), db)?;
+    let mut epoch = 0;
+    for entry in entries {
+        let i = entry.index as usize;
+        let pointless = match entry.op {
+            Op::Issue => issued.try_get_bit(i)?,
+            Op::Revoke => !issued.try_get_bit(i)? || revoked.get_bit(i),
+            Op::Reinstate => !revoked.try_get_bit(i)?
+        };
+        if pointless {
+            return Err(log_error(format!("Epoch {}: {:?} of {} doesn't make sense in its state.",
+                                         entry.epoch, entry.op, i)));
+        }
+        let revoke = entry.op == Op::Revoke;
+        if entry.op != Op::Issue {
+            let value = if revoke { El::one() } else { El::zero() };
+            tree.update(&El::from(entry.index), value, db)?;
+        }
+        if tree.root != entry.root {
+            return Err(log_error(format!("Epoch {}: root is {}; log says {}.",
+                                         entry.epoch, tree.root.to_hex(), entry.root.to_hex())));
+        }
+        match entry.op {
+            Op::Issue => issued.set_bit(i),
+            Op::Revoke => revoked.set_bit(i),
+            Op::Reinstate => revoked.unset_bit(i)
+        }
+        epoch = entry.epoch;
+    }
+    let allocator = IndexAllocator::restore(strategy, issued, revoked)?;
+    Ok(Replayed { tree, allocator, epoch })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::{make_db, make_hash_func, make_hash_params};
+    use crate::registry::Registry;
+
+    fn temp_log(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("oplog-{}-{}", name, std::process::id()));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    #[test]
+    fn replay_rebuilds_registry() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let path = temp_log("replay");
+        let mut registry = Registry::new(&hash_func, 500).unwrap();
+        registry.attach_log(OpLog::create(&path, 500).unwrap()).unwrap();
+        for _ in 0..5 {
+            registry.issue().unwrap();
+        }
+        registry.revoke(1).unwrap();
+        registry.revoke(4).unwrap();
+        registry.reinstate(1).unwrap();
+
+        let mut db = make_db();
+        let replayed = replay(&path, &hash_func, &mut db, Strategy::Sequential).unwrap();
+        assert_eq!(replayed.epoch, 8);
+        assert_eq!(&replayed.tree.root, registry.root());
+        assert_eq!(replayed.allocator.revoked(), registry.revoked());
+        assert_eq!(replayed.allocator.issued(), registry.allocator().issued());
+
+        // Reopening continues the epochs, and the recovered registry never
+        // reissues an index.
+        let log = OpLog::open(&path).unwrap();
+        assert_eq!(log.epoch(), 8);
+        assert_eq!(log.issued_count(), 5);
+        assert_eq!(log.root(), Some(registry.root()));
+        let mut recovered = Registry::with_allocator(&hash_func, replayed.allocator).unwrap();
+        recovered.attach_log(log).unwrap();
+        assert_eq!(recovered.root(), registry.root());
+        assert_eq!(recovered.issue().unwrap(), 5);
+        assert_eq!(recovered.epoch(), Some(9));
+
+        // A log that doesn't know about every issued credential can't be attached.
+        let other = temp_log("replay-other");
+        assert!(registry.attach_log(OpLog::create(&other, 500).unwrap()).is_err());
+        fs::remove_file(&other).ok();
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn detects_damage() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let path = temp_log("damage");
+        let mut registry = Registry::new(&hash_func, 64).unwrap();
+        registry.attach_log(OpLog::create(&path, 64).unwrap()).unwrap();
+        registry.issue().unwrap();
+        registry.issue().unwrap();
+        registry.revoke(0).unwrap();
+        registry.revoke(1).unwrap();
+        let good = fs::read(&path).unwrap();
+
+        // A torn append is cut off when the log is reopened.
+        fs::write(&path, &good[..good.len() - 10]).unwrap();
+        let mut log = OpLog::open(&path).unwrap();
+        assert_eq!(log.epoch(), 3);
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, HEADER_BYTES + 3 * ENTRY_BYTES);
+        let root = registry.root().clone();
+        log.append(Op::Revoke, 1, &root).unwrap();
+        let mut db = make_db();
+        assert_eq!(replay(&path, &hash_func, &mut db, Strategy::Sequential).unwrap().epoch, 4);
+
+        // A changed index fails its checksum.
+        let mut bad = good.clone();
+        bad[HEADER_BYTES + 9 + 7] ^= 1;
+        fs::write(&path, &bad).unwrap();
+        assert!(OpLog::open(&path).is_err());
+
+        // So does a dropped entry, because checksums are chained.
+        let mut dropped = good[..HEADER_BYTES].to_vec();
+        dropped.extend_from_slice(&good[HEADER_BYTES + ENTRY_BYTES..]);
+        fs::write(&path, &dropped).unwrap();
+        let mut db = make_db();
+        assert!(replay(&path, &hash_func, &mut db, Strategy::Sequential).is_err());
+
+        // A header claiming a huge capacity is rejected before allocating.
+        let mut huge = good[..HEADER_BYTES].to_vec();
+        huge[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        fs::write(&path, &huge).unwrap();
+        assert!(OpLog::open(&path).is_err());
+        let mut db = make_db();
+        assert!(replay(&path, &hash_func, &mut db, Strategy::Sequential).is_err());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn failed_append_changes_nothing() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let path = temp_log("readonly");
+        let mut registry = Registry::new(&hash_func, 64).unwrap();
+        registry.attach_log(OpLog::create(&path, 64).unwrap()).unwrap();
+        registry.issue().unwrap();
+        let root = registry.root().clone();
+
+        // Swap in a log whose file can't be written.
+        let log = OpLog { file: File::open(&path).unwrap(), capacity: 64, epoch: 1, root: Some(root.clone()),
+                          checksum: 0, issued: 1 };
+        registry.attach_log(log).unwrap();
+        assert!(registry.revoke(0).is_err());
+        assert_eq!(registry.root(), &root);
+        assert!(!registry.is_revoked(0).unwrap());
+        assert!(registry.issue().is_err());
+        assert_eq!(registry.issued(), 1);
+        assert_eq!(registry.epoch(), Some(1));
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, HEADER_BYTES + ENTRY_BYTES);
+        fs::remove_file(&path).ok();
+    }
+}
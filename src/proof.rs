@@ -0,0 +1,170 @@
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::{Arity8MerkleTreeHash, PoseidonHash8};
+use bulletproofs_amcl::utils::hash_db::HashDb;
+
+use crate::{Db, El, MerkleError, Tree};
+
+/// The 7 siblings of a node, in child order, with the node itself left out.
+pub type Siblings = [El; 7];
+
+/// Everything needed to recompute the root from one leaf: the leaf's value
+/// and, for each level from the leaves up, the siblings of the node on the
+/// path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthPath {
+    pub index: u64,
+    pub leaf: El,
+    /// siblings[0] holds the leaf's siblings; the last entry holds the
+    /// siblings of the root's child.
+    pub siblings: Vec<Siblings>
+}
+
+/// Convert a leaf index to the child numbers that lead to it from the root,
+/// most significant (the root's child) first. This is the same order as a
+/// treewalk path like /3/0/7.
+pub fn leaf_index_to_digits(index: u64, depth: usize) -> Vec<usize> {
+    let mut digits = vec![0; depth];
+    let mut n = index;
+    for d in digits.iter_mut().rev() {
+        *d = (n % 8) as usize;
+        n /= 8;
+    }
+    digits
+}
+
+/// The inverse of leaf_index_to_digits().
+pub fn digits_to_leaf_index(digits: &[usize]) -> u64 {
+    digits.iter().fold(0, |acc, d| acc * 8 + *d as u64)
+}
+
+/// Format child numbers as a treewalk-style path: "root" or "/3/0/7".
+pub fn digits_to_path(digits: &[usize]) -> String {
+    if digits.is_empty() {
+        "root".to_string()
+    } else {
+        digits.iter().map(|d| format!("/{}", d)).collect()
+    }
+}
+
+pub(crate) fn siblings_of(children: &[El], skip: usize) -> Siblings {
+    let s: Vec<&El> = children.iter().enumerate()
+        .filter(|(i, _)| *i != skip)
+        .map(|(_, c)| c)
+        .collect();
+    [s[0].clone(), s[1].clone(), s[2].clone(), s[3].clone(), s[4].clone(), s[5].clone(), s[6].clone()]
+}
+
+pub(crate) fn with_child(siblings: &Siblings, position: usize, child: El) -> Vec<El> {
+    let mut children = siblings.to_vec();
+    children.insert(position, child);
+    children
+}
+
+impl AuthPath {
+    /// Look up the path to leaf `index` in `db`, starting from `tree.root`.
+    pub fn new(tree: &Tree, db: &Db, index: u64) -> Result<AuthPath, MerkleError> {
+        if 8u64.checked_pow(tree.depth as u32).is_some_and(|capacity| index >= capacity) {
+            return Err(MerkleError::Witness(format!("Leaf {} is beyond a tree of depth {}.",
+                                                    index, tree.depth)));
+        }
+        AuthPath::walk(&tree.root, tree.depth, index, |key| Ok(db.get(&key.to_bytes())?.to_vec()))
+    }
+
+    /// Build the path to leaf `index` by following children down from
+    /// `root`, using `get_children` to look up each node on the way.
+    pub(crate) fn walk<F>(root: &El, depth: usize, index: u64, mut get_children: F)
+        -> Result<AuthPath, MerkleError>
+        where F: FnMut(&El) -> Result<Vec<El>, MerkleError> {
+        let digits = leaf_index_to_digits(index, depth);
+        let mut siblings = Vec::with_capacity(depth);
+        let mut key = root.clone();
+        for d in digits {
+            let children = get_children(&key)?;
+            siblings.push(siblings_of(&children, d));
+            key = children[d].clone();
+        }
+        siblings.reverse();
+        Ok(AuthPath { index, leaf: key, siblings })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.siblings.len()
+    }
+
+    /// Child numbers from the root down to the leaf.
+    pub fn digits(&self) -> Vec<usize> {
+        leaf_index_to_digits(self.index, self.depth())
+    }
+
+    /// Hash our way from the leaf up to the root.
+    pub fn compute_root(&self, hash_func: &PoseidonHash8) -> Result<El, MerkleError> {
+        let mut digits = self.digits();
+        digits.reverse();
+        let mut current = self.leaf.clone();
+        for (siblings, d) in self.siblings.iter().zip(digits) {
+            current = hash_func.hash(with_child(siblings, d, current))?;
+        }
+        Ok(current)
+    }
+
+    /// The hash of every node on the path: the leaf first and the root last.
+    pub fn path_hashes(&self, hash_func: &PoseidonHash8) -> Result<Vec<El>, MerkleError> {
+        let mut digits = self.digits();
+        digits.reverse();
+        let mut hashes = Vec::with_capacity(self.depth() + 1);
+        hashes.push(self.leaf.clone());
+        for (siblings, d) in self.siblings.iter().zip(digits) {
+            let parent = hash_func.hash(with_child(siblings, d, hashes.last().unwrap().clone()))?;
+            hashes.push(parent);
+        }
+        Ok(hashes)
+    }
+
+    pub fn verify(&self, hash_func: &PoseidonHash8, root: &El) -> Result<bool, MerkleError> {
+        Ok(self.compute_root(hash_func)? == *root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_db, make_hash_func, make_hash_params};
+
+    #[test]
+    fn digits_round_trip() {
+        assert_eq!(leaf_index_to_digits(300, 3), vec![4, 5, 4]);
+        assert_eq!(leaf_index_to_digits(0, 2), vec![0, 0]);
+        assert_eq!(leaf_index_to_digits(511, 3), vec![7, 7, 7]);
+        assert_eq!(digits_to_leaf_index(&[4, 5, 4]), 300);
+        assert_eq!(digits_to_path(&[4, 5, 4]), "/4/5/4");
+        assert_eq!(digits_to_path(&[]), "root");
+    }
+
+    #[test]
+    fn path_verifies() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        for i in &[3u64, 300, 301] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+
+        let path = AuthPath::new(&tree, &db, 300).unwrap();
+        assert_eq!(path.leaf, El::one());
+        assert_eq!(path.depth(), 3);
+        assert!(path.verify(&hash_func, &tree.root).unwrap());
+
+        let vacant = AuthPath::new(&tree, &db, 7).unwrap();
+        assert!(vacant.leaf.is_zero());
+        assert!(vacant.verify(&hash_func, &tree.root).unwrap());
+
+        let mut forged = path.clone();
+        forged.leaf = El::zero();
+        assert!(!forged.verify(&hash_func, &tree.root).unwrap());
+
+        match AuthPath::new(&tree, &db, 512) {
+            Err(MerkleError::Witness(_)) => (),
+            other => panic!("Expected a Witness error, not {:?}.", other)
+        }
+    }
+}
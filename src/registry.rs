@@ -0,0 +1,275 @@
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+use serde_json::{json, Value};
+
+use crate::{Db, El, MerkleError, Tree};
+use crate::allocator::{IndexAllocator, Strategy};
+use crate::bitmap::Bitmap;
+use crate::oplog::{Op, OpLog};
+use crate::proof::AuthPath;
+use crate::statement::RootStatement;
+use crate::witness::WitnessBundle;
+
+/// A revocation registry: a RevocationList2020-style bitmap and the sparse
+/// merkle tree that commits to it, kept in step. Leaf i is 1 exactly when
+/// credential i is revoked. Every change updates the tree, then the log,
+/// and only then the bitmap; if hashing or logging fails, the root and the
+/// bitmap are left as they were.
+pub struct Registry<'a> {
+    hash_func: &'a PoseidonHash8<'a>,
+    tree: Tree<'a>,
+    db: Db,
+    allocator: IndexAllocator,
+    /// If attached, every revoke and reinstate is appended here.
+    log: Option<OpLog>
+}
+
+impl<'a> Registry<'a> {
+    /// Create an empty registry with room for `capacity` credentials, in
+    /// the smallest tree that holds them, issuing indices in order.
+    pub fn new(hash_func: &'a PoseidonHash8<'a>, capacity: usize) -> Result<Registry<'a>, MerkleError> {
+        Registry::with_allocator(hash_func, IndexAllocator::new(capacity, Strategy::Sequential)?)
+    }
+
+    /// Create a registry around an allocator, which may be fresh or loaded
+    /// from saved state. The tree is rebuilt from the allocator's revocations.
+    pub fn with_allocator(hash_func: &'a PoseidonHash8<'a>, allocator: IndexAllocator)
+        -> Result<Registry<'a>, MerkleError> {
+        let mut db = crate::make_db();
+        let depth = crate::tree_depth_for(allocator.capacity());
+        let tree = crate::build_tree_from_bitmap(depth, allocator.revoked(), hash_func, &mut db)?;
+        Ok(Registry { hash_func, tree, db, allocator, log: None })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.allocator.capacity() as u64
+    }
+
+    pub fn depth(&self) -> usize {
+        self.tree.depth
+    }
+
+    /// How many credentials have been issued.
+    pub fn issued(&self) -> u64 {
+        self.allocator.issued_count() as u64
+    }
+
+    pub fn root(&self) -> &El {
+        &self.tree.root
+    }
+
+    pub fn tree(&self) -> &Tree<'a> {
+        &self.tree
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn revoked(&self) -> &Bitmap {
+        self.allocator.revoked()
+    }
+
+    /// The allocator's state, for saving.
+    pub fn allocator(&self) -> &IndexAllocator {
+        &self.allocator
+    }
+
+    /// Record every later issue, revoke and reinstate in `log`. The log must
+    /// describe this registry, so that replaying it recovers the registry:
+    /// same capacity, as many credentials issued, and ending at the current
+    /// root (or empty, if nothing has been issued).
+    pub fn attach_log(&mut self, log: OpLog) -> Result<(), MerkleError> {
+        if log.capacity() != self.allocator.capacity() {
+            return Err(MerkleError::Log(format!("Log is for capacity {}; registry has {}.",
+                                                log.capacity(), self.allocator.capacity())));
+        }
+        if log.issued_count() != self.allocator.issued_count() {
+            return Err(MerkleError::Log(format!("Log records {} credentials issued; registry has {}.",
+                                                log.issued_count(), self.allocator.issued_count())));
+        }
+        let in_step = match log.root() {
+            Some(root) => root == &self.tree.root,
+            None => self.revoked().count_ones() == 0
+        };
+        if !in_step {
+            return Err(MerkleError::Log(format!("Log ends at epoch {} with a different root.", log.epoch())));
+        }
+        self.log = Some(log);
+        Ok(())
+    }
+
+    /// The epoch of the last logged change, if a log is attached.
+    pub fn epoch(&self) -> Option<u64> {
+        self.log.as_ref().map(|log| log.epoch())
+    }
+
+    /// Allocate a free index for a new credential, and log it if a log is
+    /// attached.
+    pub fn issue(&mut self) -> Result<u64, MerkleError> {
+        match &mut self.log {
+            None => self.allocator.allocate(),
+            Some(log) => {
+                // Allocate from a copy, so a failed append leaves the allocator as it was.
+                let mut allocator = self.allocator.clone();
+                let index = allocator.allocate()?;
+                log.append(Op::Issue, index, &self.tree.root)?;
+                self.allocator = allocator;
+                Ok(index)
+            }
+        }
+    }
+
+    pub fn is_revoked(&self, index: u64) -> Result<bool, MerkleError> {
+        self.allocator.is_revoked(index)
+    }
+
+    fn set_status(&mut self, index: u64, revoke: bool) -> Result<(), MerkleError> {
+        if self.is_revoked(index)? == revoke {
+            return Err(MerkleError::Registry(format!("Credential {} is already {}.", index,
+                                                     if revoke { "revoked" } else { "active" })));
+        }
+        let value = if revoke { El::one() } else { El::zero() };
+        let previous_root = self.tree.root.clone();
+        self.tree.update(&El::from(index), value, &mut self.db)?;
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.append(if revoke { Op::Revoke } else { Op::Reinstate }, index, &self.tree.root) {
+                // update() only adds nodes, so the old root still describes the old tree.
+                self.tree.root = previous_root;
+                return Err(e);
+            }
+        }
+        if revoke {
+            self.allocator.revoke(index)
+        } else {
+            self.allocator.reinstate(index)
+        }
+    }
+
+    pub fn revoke(&mut self, index: u64) -> Result<(), MerkleError> {
+        self.set_status(index, true)
+    }
+
+    /// Undo a revocation.
+    pub fn reinstate(&mut self, index: u64) -> Result<(), MerkleError> {
+        self.set_status(index, false)
+    }
+
+    /// The current auth path for credential `index`, against root().
+    pub fn witness(&self, index: u64) -> Result<AuthPath, MerkleError> {
+        self.allocator.is_revoked(index)?;
+        AuthPath::new(&self.tree, &self.db, index)
+    }
+
+    /// Auth paths for many credentials against root(), in one bundle.
+    pub fn witness_bundle(&self, indices: &[u64]) -> Result<WitnessBundle, MerkleError> {
+        WitnessBundle::new(&self.db, &self.tree.root, self.tree.depth, indices)
+    }
+
+    /// Auth paths for every issued credential, e.g. to hand out after a
+    /// batch of revocations.
+    pub fn issued_witness_bundle(&self) -> Result<WitnessBundle, MerkleError> {
+        WitnessBundle::for_bits(&self.db, &self.tree.root, self.tree.depth, self.allocator.issued())
+    }
+
+    /// Check a holder's witness against the current root.
+    pub fn verify(&self, witness: &AuthPath) -> Result<bool, MerkleError> {
+        witness.verify(self.hash_func, &self.tree.root)
+    }
+
+    /// A statement about the current root, ready to sign. The epoch is the
+    /// attached log's, or 0 without one.
+    pub fn root_statement(&self) -> Result<RootStatement, MerkleError> {
+        Ok(RootStatement::new(self.tree.root.clone(), self.epoch().unwrap_or(0), self.tree.depth,
+                              crate::encoding::params_fingerprint(self.hash_func)?))
+    }
+
+    /// An unsigned RevocationList2020 credential for the current bitmap.
+    pub fn status_list(&self, id: &str, issuer: &str) -> Result<Value, MerkleError> {
+        Ok(json!({
+            "@context": [
+                "https://www.w3.org/2018/credentials/v1",
+                "https://w3id.org/vc-revocation-list-2020/v1"
+            ],
+            "id": id,
+            "type": ["VerifiableCredential", "RevocationList2020Credential"],
+            "issuer": issuer,
+            "credentialSubject": {
+                "id": format!("{}#list", id),
+                "type": "RevocationList2020",
+                "encodedList": self.revoked().to_encoded_list()?
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_hash_func, make_hash_params};
+
+    #[test]
+    fn keeps_bitmap_and_tree_in_step() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut registry = Registry::new(&hash_func, 500).unwrap();
+        assert_eq!(registry.depth(), 3);
+        let empty_root = registry.root().clone();
+
+        for expected in 0..3 {
+            assert_eq!(registry.issue().unwrap(), expected);
+        }
+        registry.revoke(1).unwrap();
+        assert!(registry.is_revoked(1).unwrap());
+        assert!(registry.revoke(1).is_err());
+        assert!(registry.revoke(3).is_err());
+
+        let witness = registry.witness(1).unwrap();
+        assert_eq!(witness.leaf, El::one());
+        assert!(registry.verify(&witness).unwrap());
+        let mut db = crate::make_db();
+        let rebuilt = crate::build_tree_from_bitmap(3, registry.revoked(), &hash_func, &mut db).unwrap();
+        assert_eq!(&rebuilt.root, registry.root());
+
+        let list = registry.status_list("https://example.com/status/1", "did:example:123").unwrap();
+        let decoded = Bitmap::from_revlist2020(list.to_string().as_bytes()).unwrap();
+        assert_eq!(decoded.iter_ones().collect::<Vec<usize>>(), vec![1]);
+
+        registry.reinstate(1).unwrap();
+        assert!(registry.reinstate(1).is_err());
+        assert_eq!(registry.root(), &empty_root);
+        assert!(!registry.verify(&witness).unwrap());
+    }
+
+    #[test]
+    fn rebuilds_from_allocator() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut registry = Registry::with_allocator(
+            &hash_func, IndexAllocator::new(100, Strategy::Random).unwrap()).unwrap();
+        let indices: Vec<u64> = (0..10).map(|_| registry.issue().unwrap()).collect();
+        registry.revoke(indices[3]).unwrap();
+        registry.revoke(indices[7]).unwrap();
+
+        let restarted = Registry::with_allocator(&hash_func, registry.allocator().clone()).unwrap();
+        assert_eq!(restarted.root(), registry.root());
+        assert_eq!(restarted.issued(), 10);
+        assert!(restarted.is_revoked(indices[7]).unwrap());
+
+        let bundle = restarted.issued_witness_bundle().unwrap();
+        let mut sorted = indices.clone();
+        sorted.sort_unstable();
+        assert_eq!(bundle.indices, sorted);
+        assert_eq!(bundle.auth_path(indices[7]).unwrap(), restarted.witness(indices[7]).unwrap());
+        assert_eq!(restarted.witness_bundle(&indices[..2]).unwrap().indices.len(), 2);
+    }
+
+    #[test]
+    fn runs_out_of_indices() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut registry = Registry::new(&hash_func, 2).unwrap();
+        registry.issue().unwrap();
+        registry.issue().unwrap();
+        assert!(registry.issue().is_err());
+    }
+}
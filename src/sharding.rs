@@ -0,0 +1,239 @@
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+
+use crate::{Db, El, MerkleError, Tree};
+use crate::bitmap::BitSet;
+use crate::compressed_bitmap::CompressedBitmap;
+use crate::proof::AuthPath;
+
+/// Several equally deep trees (shards), with their roots committed to by the
+/// leaves of a small top tree. Global index i lives in shard i / 8^shard_depth.
+///
+/// When every slot of the top tree holds a shard, the top root is the same
+/// as the root of one tree of depth top_depth + shard_depth. The gain is in
+/// how it's used: each update touches one shard plus the top tree, shards
+/// can be built or published separately, and a verifier that already has
+/// the current shard roots only needs the shard half of a proof.
+pub struct ShardedTree<'a> {
+    hash_func: &'a PoseidonHash8<'a>,
+    shard_depth: usize,
+    /// 8^shard_depth, checked when the shards are made.
+    shard_capacity: usize,
+    shards: Vec<Tree<'a>>,
+    /// Leaf s is the root of shard s; unused slots are zero.
+    top: Tree<'a>,
+    /// Shared by all the trees. Identical subtrees, like the empty ones,
+    /// are stored once.
+    db: Db
+}
+
+/// A proof in two layers: the leaf's path within its shard, and the path
+/// from that shard's root to the top root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardedProof {
+    pub shard: u64,
+    pub local: AuthPath,
+    /// top.leaf is the shard's root.
+    pub top: AuthPath
+}
+
+impl ShardedProof {
+    /// Check only the shard layer, for a verifier that trusts `shard_root`.
+    pub fn verify_in_shard(&self, hash_func: &PoseidonHash8, shard_root: &El) -> Result<bool, MerkleError> {
+        self.local.verify(hash_func, shard_root)
+    }
+
+    /// Check both layers against the top root.
+    pub fn verify(&self, hash_func: &PoseidonHash8, root: &El) -> Result<bool, MerkleError> {
+        Ok(self.top.index == self.shard
+           && self.verify_in_shard(hash_func, &self.top.leaf)?
+           && self.top.verify(hash_func, root)?)
+    }
+}
+
+/// Leaves per shard and in all, or None if either overflows a usize.
+fn capacities(shard_count: usize, shard_depth: usize) -> Option<(usize, usize)> {
+    let shard_capacity = 8usize.checked_pow(shard_depth as u32)?;
+    Some((shard_capacity, shard_count.checked_mul(shard_capacity)?))
+}
+
+impl<'a> ShardedTree<'a> {
+    /// Create `shard_count` empty shards, each of depth `shard_depth`.
+    pub fn new(hash_func: &'a PoseidonHash8<'a>, shard_count: usize, shard_depth: usize)
+        -> Result<ShardedTree<'a>, MerkleError> {
+        let (shard_capacity, _) = capacities(shard_count, shard_depth).ok_or_else(|| MerkleError::Registry(format!(
+            "{} shards of depth {} hold more leaves than can be indexed.", shard_count, shard_depth)))?;
+        let mut db = crate::make_db();
+        let mut shards = Vec::with_capacity(shard_count);
+        for _ in 0..shard_count {
+            shards.push(Tree::new(hash_func, shard_depth, &mut db)?);
+        }
+        ShardedTree::assemble(hash_func, shard_depth, shard_capacity, shards, db)
+    }
+
+    /// Build shards from the set bits in `b`, each with build_tree_from_bitmap().
+    /// Each shard's bits are gathered into a CompressedBitmap, so memory
+    /// grows with the set bits, not with the capacity.
+    pub fn from_bits<B: BitSet>(hash_func: &'a PoseidonHash8<'a>, shard_count: usize, shard_depth: usize,
+                                b: &B) -> Result<ShardedTree<'a>, MerkleError> {
+        let too_many = MerkleError::TooManyBits {
+            bit_count: b.len(),
+            depth: crate::tree_depth_for(shard_count).saturating_add(shard_depth)
+        };
+        let shard_capacity = match capacities(shard_count, shard_depth) {
+            Some((shard_capacity, total)) if b.len() <= total => shard_capacity,
+            _ => return Err(too_many)
+        };
+        let mut db = crate::make_db();
+        let mut shards = Vec::with_capacity(shard_count);
+        let mut next = b.next_one(0);
+        for s in 0..shard_count {
+            // Can't overflow: (s + 1) * shard_capacity <= the total checked above.
+            let start = s * shard_capacity;
+            let end = start + shard_capacity;
+            let mut local = CompressedBitmap::new(shard_capacity)?;
+            while let Some(i) = next {
+                if i >= end {
+                    break
+                }
+                local.set_bit(i - start);
+                next = b.next_one(i + 1);
+            }
+            shards.push(crate::build_tree_from_bitmap(shard_depth, &local, hash_func, &mut db)?);
+        }
+        ShardedTree::assemble(hash_func, shard_depth, shard_capacity, shards, db)
+    }
+
+    fn assemble(hash_func: &'a PoseidonHash8<'a>, shard_depth: usize, shard_capacity: usize,
+                shards: Vec<Tree<'a>>, mut db: Db)
+        -> Result<ShardedTree<'a>, MerkleError> {
+        let mut top = Tree::new(hash_func, crate::tree_depth_for(shards.len()), &mut db)?;
+        for (s, shard) in shards.iter().enumerate() {
+            top.update(&El::from(s as u64), shard.root.clone(), &mut db)?;
+        }
+        Ok(ShardedTree { hash_func, shard_depth, shard_capacity, shards, top, db })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_depth(&self) -> usize {
+        self.shard_depth
+    }
+
+    pub fn top_depth(&self) -> usize {
+        self.top.depth
+    }
+
+    pub fn shard_capacity(&self) -> u64 {
+        self.shard_capacity as u64
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.shard_capacity() * self.shards.len() as u64
+    }
+
+    /// The top-level commitment to every shard.
+    pub fn root(&self) -> &El {
+        &self.top.root
+    }
+
+    pub fn shard_root(&self, shard: usize) -> &El {
+        &self.shards[shard].root
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Map a global index to (shard, index within the shard).
+    pub fn locate(&self, index: u64) -> Result<(usize, u64), MerkleError> {
+        if index >= self.capacity() {
+            return Err(MerkleError::Registry(format!(
+                "Index {} is beyond {} shards of {} leaves.", index, self.shards.len(), self.shard_capacity())));
+        }
+        Ok(((index / self.shard_capacity()) as usize, index % self.shard_capacity()))
+    }
+
+    /// Set a leaf, then record its shard's new root in the top tree.
+    pub fn update(&mut self, index: u64, value: El) -> Result<(), MerkleError> {
+        let (shard, local) = self.locate(index)?;
+        self.shards[shard].update(&El::from(local), value, &mut self.db)?;
+        let shard_root = self.shards[shard].root.clone();
+        self.top.update(&El::from(shard as u64), shard_root, &mut self.db)?;
+        Ok(())
+    }
+
+    pub fn proof(&self, index: u64) -> Result<ShardedProof, MerkleError> {
+        let (shard, local) = self.locate(index)?;
+        Ok(ShardedProof {
+            shard: shard as u64,
+            local: AuthPath::new(&self.shards[shard], &self.db, local)?,
+            top: AuthPath::new(&self.top, &self.db, shard as u64)?
+        })
+    }
+
+    pub fn verify(&self, proof: &ShardedProof) -> Result<bool, MerkleError> {
+        proof.verify(self.hash_func, self.root())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitmap::Bitmap;
+    use crate::{make_hash_func, make_hash_params};
+
+    #[test]
+    fn proves_across_layers() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut sharded = ShardedTree::new(&hash_func, 3, 2).unwrap();
+        assert_eq!(sharded.capacity(), 192);
+        assert_eq!(sharded.locate(130).unwrap(), (2, 2));
+        assert!(sharded.locate(192).is_err());
+
+        let mut b = Bitmap::new(192).unwrap();
+        for i in &[0u64, 65, 130, 191] {
+            sharded.update(*i, El::one()).unwrap();
+            b.set_bit(*i as usize);
+        }
+        let proof = sharded.proof(130).unwrap();
+        assert_eq!(proof.local.leaf, El::one());
+        assert!(sharded.verify(&proof).unwrap());
+        assert!(proof.verify_in_shard(&hash_func, sharded.shard_root(2)).unwrap());
+        assert!(!proof.verify_in_shard(&hash_func, sharded.shard_root(1)).unwrap());
+        let mut moved = proof.clone();
+        moved.shard = 1;
+        assert!(!sharded.verify(&moved).unwrap());
+
+        let built = ShardedTree::from_bits(&hash_func, 3, 2, &b).unwrap();
+        assert_eq!(built.root(), sharded.root());
+        assert!(ShardedTree::from_bits(&hash_func, 2, 2, &b).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_shards() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let b = CompressedBitmap::new(100).unwrap();
+        assert!(matches!(ShardedTree::from_bits(&hash_func, 2, 22, &b), Err(MerkleError::TooManyBits { .. })));
+        assert!(matches!(ShardedTree::from_bits(&hash_func, usize::MAX, 2, &b),
+                         Err(MerkleError::TooManyBits { .. })));
+        assert!(ShardedTree::new(&hash_func, 2, 22).is_err());
+    }
+
+    #[test]
+    fn full_top_matches_one_deep_tree() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut sharded = ShardedTree::new(&hash_func, 8, 2).unwrap();
+        let mut db = crate::make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        for i in &[9u64, 300, 511] {
+            sharded.update(*i, El::one()).unwrap();
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+        assert_eq!(sharded.root(), &tree.root);
+    }
+}
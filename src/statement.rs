@@ -0,0 +1,276 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use amcl_wrapper::extension_field_gt::GT;
+use amcl_wrapper::field_elem::FieldElement;
+use amcl_wrapper::group_elem::GroupElement;
+use amcl_wrapper::group_elem_g1::G1;
+use amcl_wrapper::group_elem_g2::G2;
+use serde_json::{json, Value};
+
+use crate::{El, MerkleError};
+
+/// Prefixed to every signed message, so a signature over a root statement
+/// can't be passed off as a signature over anything else made with the key.
+const DOMAIN: &[u8] = b"merklespike/root-statement/v1";
+
+/// The current version of the root statement format.
+pub const STATEMENT_VERSION: u8 = 1;
+
+fn encoding_error(msg: &str) -> MerkleError {
+    MerkleError::Encoding(msg.to_string())
+}
+
+fn to_base64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn from_base64(v: &Value, field: &str) -> Result<Vec<u8>, MerkleError> {
+    let s = v[field].as_str()
+        .ok_or_else(|| MerkleError::Encoding(format!("JSON has no \"{}\" string.", field)))?;
+    Ok(base64::decode_config(s, base64::URL_SAFE_NO_PAD)?)
+}
+
+/// A BLS secret key on bls381. Signatures are in G1 and public keys in G2,
+/// which keeps signatures short, since many more of them get published.
+#[derive(Clone)]
+pub struct SigningKey(FieldElement);
+
+/// The public half of a SigningKey.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyingKey(G2);
+
+impl SigningKey {
+    pub fn random() -> SigningKey {
+        SigningKey(FieldElement::random())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SigningKey, MerkleError> {
+        let sk = FieldElement::from_bytes(bytes)
+            .map_err(|e| MerkleError::Encoding(format!("Bad signing key. {:?}", e)))?;
+        if sk.is_zero() {
+            return Err(encoding_error("Signing key is zero."));
+        }
+        Ok(SigningKey(sk))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(G2::generator().scalar_mul_const_time(&self.0))
+    }
+}
+
+impl VerifyingKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<VerifyingKey, MerkleError> {
+        let pk = G2::from_bytes(bytes)
+            .map_err(|e| MerkleError::Encoding(format!("Bad verifying key. {:?}", e)))?;
+        if pk.is_identity() {
+            return Err(encoding_error("Verifying key is the identity."));
+        }
+        Ok(VerifyingKey(pk))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+}
+
+/// What an issuer vouches for when it publishes a root: this was the root of
+/// its tree at this epoch and time, for a tree of this depth built with hash
+/// params matching this fingerprint (see encoding::params_fingerprint).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RootStatement {
+    pub root: El,
+    /// The op log epoch the root belongs to (see oplog); 0 without a log.
+    pub epoch: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub depth: usize,
+    pub fingerprint: [u8; 8]
+}
+
+/// A root statement and a BLS signature over it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedRoot {
+    pub statement: RootStatement,
+    signature: G1
+}
+
+impl RootStatement {
+    /// A statement about `root`, timestamped now.
+    pub fn new(root: El, epoch: u64, depth: usize, fingerprint: [u8; 8]) -> RootStatement {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        RootStatement { root, epoch, timestamp, depth, fingerprint }
+    }
+
+    /// The bytes that get signed (integers are big-endian):
+    ///
+    /// ```text
+    /// domain       DOMAIN
+    /// version      1 byte
+    /// fingerprint  8 bytes
+    /// depth        1 byte
+    /// epoch        8 bytes
+    /// timestamp    8 bytes
+    /// root         EL_BYTES
+    /// ```
+    pub fn message(&self) -> Result<Vec<u8>, MerkleError> {
+        if self.depth > u8::MAX as usize {
+            return Err(MerkleError::Encoding(format!("Depth {} doesn't fit in a byte.", self.depth)));
+        }
+        let mut m = DOMAIN.to_vec();
+        m.push(STATEMENT_VERSION);
+        m.extend_from_slice(&self.fingerprint);
+        m.push(self.depth as u8);
+        m.extend_from_slice(&self.epoch.to_be_bytes());
+        m.extend_from_slice(&self.timestamp.to_be_bytes());
+        m.extend_from_slice(&self.root.to_bytes());
+        Ok(m)
+    }
+
+    pub fn sign(self, key: &SigningKey) -> Result<SignedRoot, MerkleError> {
+        let signature = G1::from_msg_hash(&self.message()?).scalar_mul_const_time(&key.0);
+        Ok(SignedRoot { statement: self, signature })
+    }
+}
+
+impl SignedRoot {
+    /// Check the signature: e(signature, g2) == e(H(message), key).
+    pub fn verify(&self, key: &VerifyingKey) -> Result<bool, MerkleError> {
+        if self.signature.is_identity() || key.0.is_identity() {
+            return Ok(false);
+        }
+        let h = G1::from_msg_hash(&self.statement.message()?);
+        Ok(GT::ate_pairing(&self.signature, &G2::generator()) == GT::ate_pairing(&h, &key.0))
+    }
+
+    /// Check the signature, and that the statement is about `root`.
+    pub fn verify_root(&self, key: &VerifyingKey, root: &El) -> Result<bool, MerkleError> {
+        Ok(&self.statement.root == root && self.verify(key)?)
+    }
+
+    /// A JSON object that can be published on its own, or embedded in a
+    /// status list credential with embed(). The verifying key is included
+    /// for convenience; verifiers should get it from somewhere they trust.
+    pub fn to_json(&self, key: &VerifyingKey) -> Value {
+        let s = &self.statement;
+        json!({
+            "type": "MerkleRootStatement8",
+            "version": STATEMENT_VERSION,
+            "root": s.root.to_hex(),
+            "epoch": s.epoch,
+            "timestamp": s.timestamp,
+            "depth": s.depth,
+            "fingerprint": hex::encode(s.fingerprint),
+            "verificationKey": to_base64(&key.to_bytes()),
+            "signature": to_base64(&self.signature.to_bytes())
+        })
+    }
+
+    /// Decode the output of to_json(). This doesn't check the signature.
+    pub fn from_json(v: &Value) -> Result<SignedRoot, MerkleError> {
+        if v["version"].as_u64() != Some(STATEMENT_VERSION as u64) {
+            return Err(encoding_error("Missing or unsupported root statement version."));
+        }
+        let number = |field: &str| v[field].as_u64()
+            .ok_or_else(|| MerkleError::Encoding(format!("JSON has no \"{}\" number.", field)));
+        let root = v["root"].as_str()
+            .and_then(|s| El::from_hex(s.to_string()).ok())
+            .ok_or_else(|| encoding_error("JSON has no valid \"root\"."))?;
+        let fingerprint = v["fingerprint"].as_str()
+            .and_then(|s| hex::decode(s).ok())
+            .filter(|b| b.len() == 8)
+            .ok_or_else(|| encoding_error("JSON has no valid \"fingerprint\"."))?;
+        let signature = G1::from_bytes(&from_base64(v, "signature")?)
+            .map_err(|e| MerkleError::Encoding(format!("Bad signature. {:?}", e)))?;
+        let mut statement = RootStatement {
+            root,
+            epoch: number("epoch")?,
+            timestamp: number("timestamp")?,
+            depth: number("depth")? as usize,
+            fingerprint: [0u8; 8]
+        };
+        statement.fingerprint.copy_from_slice(&fingerprint);
+        Ok(SignedRoot { statement, signature })
+    }
+
+    /// The verifying key a to_json() object names. Only useful once it has
+    /// been matched against a key the verifier trusts.
+    pub fn key_from_json(v: &Value) -> Result<VerifyingKey, MerkleError> {
+        VerifyingKey::from_bytes(&from_base64(v, "verificationKey")?)
+    }
+
+    /// Add this statement to a RevocationList2020 credential, as
+    /// credentialSubject.rootStatement.
+    pub fn embed(&self, key: &VerifyingKey, credential: &mut Value) -> Result<(), MerkleError> {
+        match credential.get_mut("credentialSubject").and_then(Value::as_object_mut) {
+            Some(subject) => {
+                subject.insert("rootStatement".to_string(), self.to_json(key));
+                Ok(())
+            },
+            None => Err(encoding_error("Credential has no credentialSubject object."))
+        }
+    }
+
+    /// The statement embedded in a credential by embed().
+    pub fn from_credential(credential: &Value) -> Result<SignedRoot, MerkleError> {
+        SignedRoot::from_json(&credential["credentialSubject"]["rootStatement"])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_hash_func, make_hash_params};
+    use crate::bitmap::Bitmap;
+    use crate::registry::Registry;
+
+    #[test]
+    fn signs_and_verifies() {
+        let key = SigningKey::random();
+        let public = key.verifying_key();
+        let statement = RootStatement::new(El::from(42u64), 7, 5, [1, 2, 3, 4, 5, 6, 7, 8]);
+        let signed = statement.clone().sign(&key).unwrap();
+        assert!(signed.verify(&public).unwrap());
+        assert!(signed.verify_root(&public, &El::from(42u64)).unwrap());
+        assert!(!signed.verify_root(&public, &El::from(43u64)).unwrap());
+        assert!(!signed.verify(&SigningKey::random().verifying_key()).unwrap());
+
+        let mut altered = signed.clone();
+        altered.statement.epoch = 8;
+        assert!(!altered.verify(&public).unwrap());
+
+        let restored = SigningKey::from_bytes(&key.to_bytes()).unwrap();
+        assert_eq!(restored.verifying_key(), public);
+        assert_eq!(VerifyingKey::from_bytes(&public.to_bytes()).unwrap(), public);
+        assert!(VerifyingKey::from_bytes(&[0u8; 3]).is_err());
+    }
+
+    #[test]
+    fn embeds_in_status_list() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut registry = Registry::new(&hash_func, 100).unwrap();
+        registry.issue().unwrap();
+        registry.revoke(0).unwrap();
+        let key = SigningKey::random();
+        let public = key.verifying_key();
+        let signed = registry.root_statement().unwrap().sign(&key).unwrap();
+
+        let mut list = registry.status_list("https://example.com/status/1", "did:example:123").unwrap();
+        signed.embed(&public, &mut list).unwrap();
+        let published = list.to_string();
+        let v: Value = serde_json::from_str(&published).unwrap();
+        let decoded = SignedRoot::from_credential(&v).unwrap();
+        assert_eq!(decoded, signed);
+        assert_eq!(SignedRoot::key_from_json(&v["credentialSubject"]["rootStatement"]).unwrap(), public);
+        assert!(decoded.verify_root(&public, registry.root()).unwrap());
+        let bits = Bitmap::from_revlist2020(published.as_bytes()).unwrap();
+        assert_eq!(bits.iter_ones().collect::<Vec<usize>>(), vec![0]);
+
+        assert!(signed.embed(&public, &mut json!({})).is_err());
+        assert!(SignedRoot::from_json(&json!({ "version": 1 })).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+use bulletproofs_amcl::utils::hash_db::HashDb;
+
+use crate::{Db, El, MerkleError, Tree};
+
+/// Roughly how many bytes one hash db entry takes when serialized: a key
+/// plus 8 children, each a 48-byte field element.
+pub const SERIALIZED_ENTRY_BYTES: usize = 9 * crate::EL_BYTES;
+
+/// What one level of the tree looks like. Level 0 is the root; level
+/// `depth` holds the leaves.
+#[derive(Debug, Clone, Default)]
+pub struct LevelStats {
+    pub level: usize,
+    /// 8^level: how many node positions this level has. Saturates at
+    /// u64::MAX from level 22 down, as do the position counts below.
+    pub positions: u64,
+    /// Positions occupied by the default empty subtree (or a zero leaf).
+    pub empty_positions: u64,
+    /// Distinct non-empty nodes (or non-zero leaf values).
+    pub unique: usize,
+    /// Distinct non-empty nodes that occupy more than one position.
+    pub shared: usize,
+    /// The most positions any one non-empty node occupies.
+    pub max_reuse: u64,
+    /// Estimated serialized size of this level's db entries.
+    pub bytes: usize
+}
+
+/// A structural audit of the part of a hash db reachable from one root.
+#[derive(Debug, Clone)]
+pub struct TreeStats {
+    pub depth: usize,
+    pub levels: Vec<LevelStats>,
+    /// Distinct db entries reachable from the root.
+    pub reachable: usize,
+    /// Entries in the db that no path from the root reaches (old roots,
+    /// nodes replaced by updates, empty subtrees that are now full...).
+    pub unreachable: usize,
+    /// Non-zero leaves.
+    pub set_leaves: u64
+}
+
+impl TreeStats {
+    /// Walk `db` level by level from `tree.root`. Each distinct node is
+    /// fetched once, however many positions it occupies.
+    pub fn new(tree: &Tree, db: &Db, hash_func: &PoseidonHash8) -> Result<TreeStats, MerkleError> {
+        let depth = tree.depth;
+        let empty_hashes = crate::empty_subtree_hashes(hash_func, depth)?;
+        let mut levels = Vec::with_capacity(depth + 1);
+        let mut reachable = 0;
+        let mut set_leaves = 0;
+
+        // Distinct nodes at the current level, with the number of positions
+        // each occupies.
+        let mut current: HashMap<Vec<u8>, (El, u64)> = HashMap::new();
+        current.insert(tree.root.to_bytes(), (tree.root.clone(), 1));
+
+        for level in 0..=depth {
+            let height = depth - level;
+            let mut stats = LevelStats {
+                level,
+                positions: 8u64.checked_pow(level as u32).unwrap_or(u64::MAX),
+                ..Default::default()
+            };
+            let mut next: HashMap<Vec<u8>, (El, u64)> = HashMap::new();
+            for (key_bytes, (key, weight)) in current.iter() {
+                if *key == empty_hashes[height] {
+                    stats.empty_positions = stats.empty_positions.saturating_add(*weight);
+                } else {
+                    if height == 0 {
+                        set_leaves += weight;
+                    }
+                    stats.unique += 1;
+                    if *weight > 1 {
+                        stats.shared += 1;
+                    }
+                    if *weight > stats.max_reuse {
+                        stats.max_reuse = *weight;
+                    }
+                }
+                if height == 0 {
+                    continue
+                }
+                reachable += 1;
+                stats.bytes += SERIALIZED_ENTRY_BYTES;
+                let children = db.get(key_bytes)?;
+                for child in children.iter() {
+                    let entry = next.entry(child.to_bytes()).or_insert((child.clone(), 0));
+                    entry.1 = entry.1.saturating_add(*weight);
+                }
+            }
+            levels.push(stats);
+            current = next;
+        }
+
+        Ok(TreeStats {
+            depth,
+            levels,
+            reachable,
+            unreachable: db.len().saturating_sub(reachable),
+            set_leaves
+        })
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.levels.iter().map(|l| l.bytes).sum()
+    }
+}
+
+impl fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "depth = {}; {} leaves set; {} reachable entries ({} bytes); {} unreachable",
+                 self.depth, self.set_leaves, self.reachable, self.total_bytes(), self.unreachable)?;
+        writeln!(f, "{:>5} {:>14} {:>14} {:>10} {:>8} {:>10} {:>12}",
+                 "level", "positions", "empty", "unique", "shared", "max reuse", "bytes")?;
+        for l in &self.levels {
+            writeln!(f, "{:>5} {:>14} {:>14} {:>10} {:>8} {:>10} {:>12}",
+                     l.level, l.positions, l.empty_positions, l.unique, l.shared, l.max_reuse, l.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_db, make_hash_func, make_hash_params};
+
+    #[test]
+    fn counts_levels() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        // Leaves 0 and 64 sit in identical subtrees (/0 and /1), so those
+        // nodes are stored once and shared.
+        for i in &[0u64, 64, 300] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+        let stats = TreeStats::new(&tree, &db, &hash_func).unwrap();
+        assert_eq!(stats.set_leaves, 3);
+        assert_eq!(stats.levels[0].unique, 1);
+        assert_eq!(stats.levels[1].unique, 2);
+        assert_eq!(stats.levels[1].shared, 1);
+        assert_eq!(stats.levels[1].empty_positions, 5);
+        assert_eq!(stats.levels[2].unique, 2);
+        assert_eq!(stats.levels[2].shared, 1);
+        assert_eq!(stats.levels[2].max_reuse, 2);
+        assert_eq!(stats.levels[3].unique, 1);
+        // Updates leave old roots and replaced nodes behind.
+        assert!(stats.unreachable > 0);
+        assert_eq!(stats.reachable + stats.unreachable, db.len());
+    }
+
+    #[test]
+    fn deep_tree_saturates() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 23, &mut db).unwrap();
+        tree.update(&El::from(5u64), El::one(), &mut db).unwrap();
+        let stats = TreeStats::new(&tree, &db, &hash_func).unwrap();
+        assert_eq!(stats.set_leaves, 1);
+        assert_eq!(stats.levels[21].positions, 1 << 63);
+        assert_eq!(stats.levels[22].positions, u64::MAX);
+        assert_eq!(stats.levels[23].empty_positions, u64::MAX);
+    }
+}
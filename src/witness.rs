@@ -0,0 +1,408 @@
+use std::collections::BTreeMap;
+
+use bulletproofs_amcl::r1cs::gadgets::merkle_tree_hash::PoseidonHash8;
+use bulletproofs_amcl::utils::hash_db::HashDb;
+use serde_json::{json, Value};
+
+use crate::{Db, El, MerkleError, Tree, EL_BYTES};
+use crate::bitmap::BitSet;
+use crate::encoding::Reader;
+use crate::proof::{leaf_index_to_digits, AuthPath};
+
+/// One entry in an update feed: a leaf that changed, and the new hash of
+/// every node on its path. A holder whose path passes near this leaf needs
+/// exactly one of those hashes to bring their witness up to date, so a feed
+/// of these is all they need to follow the root without the Db.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafUpdate {
+    pub index: u64,
+    /// nodes[0] is the new leaf value; nodes[depth] is the new root.
+    pub nodes: Vec<El>
+}
+
+impl LeafUpdate {
+    /// Describe leaf `index` as it stands in `tree` now. Call this after the
+    /// leaf changes, either once per update or once per changed leaf after a
+    /// batch of updates; both produce feeds that holders can apply in order.
+    pub fn new(tree: &Tree, db: &Db, index: u64, hash_func: &PoseidonHash8)
+        -> Result<LeafUpdate, MerkleError> {
+        LeafUpdate::from_path(&AuthPath::new(tree, db, index)?, hash_func)
+    }
+
+    pub fn from_path(path: &AuthPath, hash_func: &PoseidonHash8) -> Result<LeafUpdate, MerkleError> {
+        Ok(LeafUpdate { index: path.index, nodes: path.path_hashes(hash_func)? })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    pub fn value(&self) -> &El {
+        &self.nodes[0]
+    }
+
+    /// The root of the tree right after this update.
+    pub fn root(&self) -> &El {
+        &self.nodes[self.depth()]
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "index": self.index,
+            "nodes": self.nodes.iter().map(|n| n.to_hex()).collect::<Vec<String>>()
+        })
+    }
+
+    pub fn from_json(v: &Value) -> Result<LeafUpdate, MerkleError> {
+        let bad = || MerkleError::Encoding("Bad leaf update JSON.".to_string());
+        let index = v["index"].as_u64().ok_or_else(bad)?;
+        let nodes = v["nodes"].as_array().ok_or_else(bad)?.iter()
+            .map(|n| {
+                let hex = n.as_str().ok_or_else(bad)?;
+                El::from_hex(hex.to_string())
+                    .map_err(|e| MerkleError::Encoding(format!("Bad field element. {:?}", e)))
+            })
+            .collect::<Result<Vec<El>, MerkleError>>()?;
+        if nodes.is_empty() {
+            return Err(bad());
+        }
+        Ok(LeafUpdate { index, nodes })
+    }
+}
+
+/// Set leaf `index` to `value` and return the feed entry that describes the
+/// change.
+pub fn update_leaf(tree: &mut Tree, db: &mut Db, hash_func: &PoseidonHash8, index: u64, value: El)
+    -> Result<LeafUpdate, MerkleError> {
+    tree.update(&El::from(index), value, db)?;
+    LeafUpdate::new(tree, db, index, hash_func)
+}
+
+impl AuthPath {
+    /// Bring this path up to date with one change to the tree. Only the
+    /// sibling at the level where the changed leaf's path branches off from
+    /// ours is replaced; if the changed leaf is ours, only the leaf value is.
+    pub fn apply_update(&mut self, update: &LeafUpdate) -> Result<(), MerkleError> {
+        let depth = self.depth();
+        if update.nodes.len() != depth + 1 {
+            return Err(MerkleError::Witness(format!(
+                "Update to leaf {} is for a tree of depth {}, not {}.",
+                update.index, update.nodes.len().saturating_sub(1), depth)));
+        }
+        if 8u64.checked_pow(depth as u32).is_some_and(|capacity| update.index >= capacity) {
+            return Err(MerkleError::Witness(format!(
+                "Leaf {} is beyond a tree of depth {}.", update.index, depth)));
+        }
+        let ours = self.digits();
+        let theirs = leaf_index_to_digits(update.index, depth);
+        match ours.iter().zip(&theirs).position(|(a, b)| a != b) {
+            None => self.leaf = update.nodes[0].clone(),
+            Some(level) => {
+                // The paths share `level` ancestors below the root; the
+                // changed node is our sibling one level further down.
+                let height = depth - level - 1;
+                let (d, their_d) = (ours[level], theirs[level]);
+                let slot = if their_d < d { their_d } else { their_d - 1 };
+                self.siblings[height][slot] = update.nodes[height].clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Return a copy of this path with every update in the feed applied.
+    pub fn refresh(&self, updates: &[LeafUpdate]) -> Result<AuthPath, MerkleError> {
+        let mut path = self.clone();
+        for u in updates {
+            path.apply_update(u)?;
+        }
+        Ok(path)
+    }
+}
+
+/// A serialized bundle node: its key and its 8 children.
+const NODE_BYTES: usize = 9 * EL_BYTES;
+
+/// The current version of the WitnessBundle binary format.
+pub const BUNDLE_FORMAT_VERSION: u8 = 1;
+
+/// Make sure bundle indices are strictly ascending (so sorted and unique,
+/// which auth_path()'s binary search relies on) and all fit in the tree.
+fn check_indices(indices: &[u64], depth: usize) -> Result<(), MerkleError> {
+    if indices.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(MerkleError::Witness("Bundle indices aren't sorted and unique.".to_string()));
+    }
+    if let (Some(last), Some(capacity)) = (indices.last(), 8u64.checked_pow(depth as u32)) {
+        if *last >= capacity {
+            return Err(MerkleError::Witness(format!(
+                "Leaf {} is beyond a tree of depth {}.", last, depth)));
+        }
+    }
+    Ok(())
+}
+
+/// Authentication paths for many leaves at once, stored as the distinct
+/// nodes on their paths. Paths to neighboring leaves share most of their
+/// upper levels, so this is much smaller than the paths themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WitnessBundle {
+    pub depth: usize,
+    pub root: El,
+    /// Sorted, without duplicates.
+    pub indices: Vec<u64>,
+    /// The children of every interior node on some index's path, by key.
+    nodes: BTreeMap<Vec<u8>, Vec<El>>
+}
+
+impl WitnessBundle {
+    /// Collect the paths to `indices` from the tree under `root`. The tree
+    /// is read one level at a time, and each distinct node is looked up
+    /// once, however many of the paths pass through it.
+    pub fn new(db: &Db, root: &El, depth: usize, indices: &[u64]) -> Result<WitnessBundle, MerkleError> {
+        let mut indices = indices.to_vec();
+        indices.sort();
+        indices.dedup();
+        check_indices(&indices, depth)?;
+
+        let mut nodes = BTreeMap::new();
+        // The nodes on some path at the current level, as (position within
+        // the level, key), in position order.
+        let mut frontier = if indices.is_empty() { Vec::new() } else { vec![(0u64, root.clone())] };
+        for level in 0..depth {
+            let shift = 3 * (depth - level - 1) as u32;
+            let mut wanted: Vec<u64> = indices.iter().map(|i| i >> shift).collect();
+            wanted.dedup();
+            let mut wanted = wanted.into_iter().peekable();
+            let mut next = Vec::with_capacity(frontier.len());
+            for (position, key) in frontier {
+                let key_bytes = key.to_bytes();
+                if !nodes.contains_key(&key_bytes) {
+                    nodes.insert(key_bytes.clone(), db.get(&key_bytes)?.to_vec());
+                }
+                let children = &nodes[&key_bytes];
+                while let Some(child) = wanted.peek() {
+                    if child >> 3 != position {
+                        break
+                    }
+                    next.push((*child, children[(child & 7) as usize].clone()));
+                    wanted.next();
+                }
+            }
+            frontier = next;
+        }
+        Ok(WitnessBundle { depth, root: root.clone(), indices, nodes })
+    }
+
+    /// Collect the paths to every set bit in `b`, such as a bitmap of the
+    /// credentials that were issued and not revoked.
+    pub fn for_bits<B: BitSet>(db: &Db, root: &El, depth: usize, b: &B) -> Result<WitnessBundle, MerkleError> {
+        let mut indices = Vec::with_capacity(b.count_ones());
+        let mut from = 0;
+        while let Some(i) = b.next_one(from) {
+            indices.push(i as u64);
+            from = i + 1;
+        }
+        WitnessBundle::new(db, root, depth, &indices)
+    }
+
+    /// How many distinct interior nodes the bundle holds.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn auth_path(&self, index: u64) -> Result<AuthPath, MerkleError> {
+        if self.indices.binary_search(&index).is_err() {
+            return Err(MerkleError::Witness(format!("Leaf {} isn't in this bundle.", index)));
+        }
+        AuthPath::walk(&self.root, self.depth, index, |key| {
+            self.nodes.get(&key.to_bytes()).cloned()
+                .ok_or_else(|| MerkleError::Witness(format!("Bundle has no node {}.", key.to_hex())))
+        })
+    }
+
+    /// Every path in the bundle, in index order.
+    pub fn auth_paths(&self) -> Result<Vec<AuthPath>, MerkleError> {
+        self.indices.iter().map(|i| self.auth_path(*i)).collect()
+    }
+
+    /// Serialize as: version (1 byte); depth (1 byte); the root; index
+    /// count (4 bytes) and indices (8 bytes each, ascending); node count
+    /// (4 bytes) and nodes, each a key followed by its 8 children. Integers
+    /// are big-endian; field elements take EL_BYTES each.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(2 + EL_BYTES + 4 + 8 * self.indices.len()
+                                         + 4 + NODE_BYTES * self.nodes.len());
+        out.push(BUNDLE_FORMAT_VERSION);
+        out.push(self.depth as u8);
+        out.extend_from_slice(&self.root.to_bytes());
+        out.extend_from_slice(&(self.indices.len() as u32).to_be_bytes());
+        for i in &self.indices {
+            out.extend_from_slice(&i.to_be_bytes());
+        }
+        out.extend_from_slice(&(self.nodes.len() as u32).to_be_bytes());
+        for (key, children) in &self.nodes {
+            out.extend_from_slice(key);
+            for c in children {
+                out.extend_from_slice(&c.to_bytes());
+            }
+        }
+        out
+    }
+
+    /// Decode the output of to_bytes(). Counts are checked against the
+    /// length of the data before anything is allocated for them.
+    pub fn from_bytes(bytes: &[u8]) -> Result<WitnessBundle, MerkleError> {
+        let mut r = Reader::new(bytes);
+        let version = r.u8()?;
+        if version != BUNDLE_FORMAT_VERSION {
+            return Err(MerkleError::Encoding(format!("Unsupported bundle format version {}.", version)));
+        }
+        let depth = r.u8()? as usize;
+        let root = r.el()?;
+        let index_count = r.u32()? as usize;
+        if index_count > r.remaining() / 8 {
+            return Err(MerkleError::Encoding(format!("Bundle claims {} indices but is too short.", index_count)));
+        }
+        let mut indices = Vec::with_capacity(index_count);
+        for _ in 0..index_count {
+            indices.push(r.u64()?);
+        }
+        check_indices(&indices, depth)?;
+        let node_count = r.u32()? as usize;
+        if node_count > r.remaining() / NODE_BYTES {
+            return Err(MerkleError::Encoding(format!("Bundle claims {} nodes but is too short.", node_count)));
+        }
+        let mut nodes = BTreeMap::new();
+        for _ in 0..node_count {
+            let key = r.take(EL_BYTES)?.to_vec();
+            let mut children = Vec::with_capacity(8);
+            for _ in 0..8 {
+                children.push(r.el()?);
+            }
+            nodes.insert(key, children);
+        }
+        r.finish()?;
+        Ok(WitnessBundle { depth, root, indices, nodes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_db, make_hash_func, make_hash_params};
+
+    #[test]
+    fn refreshed_path_matches_new_tree() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        for i in &[3u64, 300] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+        let old = AuthPath::new(&tree, &db, 300).unwrap();
+
+        // Same bottom node, same /4 subtree, across the tree, and ours.
+        let mut feed = Vec::new();
+        for i in &[301u64, 310, 7, 3] {
+            feed.push(update_leaf(&mut tree, &mut db, &hash_func, *i, El::one()).unwrap());
+        }
+        feed.push(update_leaf(&mut tree, &mut db, &hash_func, 300, El::zero()).unwrap());
+        assert_eq!(feed.last().unwrap().root(), &tree.root);
+
+        let json = feed[1].to_json();
+        assert_eq!(LeafUpdate::from_json(&json).unwrap(), feed[1]);
+
+        let refreshed = old.refresh(&feed).unwrap();
+        assert_eq!(refreshed, AuthPath::new(&tree, &db, 300).unwrap());
+        assert!(refreshed.verify(&hash_func, &tree.root).unwrap());
+        assert!(!old.verify(&hash_func, &tree.root).unwrap());
+
+        // A feed recorded once at the end of a batch works too.
+        let old = refreshed;
+        for i in &[0u64, 299, 302] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+        let batch: Vec<LeafUpdate> = [0u64, 299, 302].iter()
+            .map(|i| LeafUpdate::new(&tree, &db, *i, &hash_func).unwrap())
+            .collect();
+        assert!(old.refresh(&batch).unwrap().verify(&hash_func, &tree.root).unwrap());
+    }
+
+    #[test]
+    fn rejects_update_for_other_depth() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        let path = AuthPath::new(&tree, &db, 1).unwrap();
+        let update = LeafUpdate { index: 1, nodes: vec![El::one(); 3] };
+        assert!(path.refresh(&[update]).is_err());
+    }
+
+    #[test]
+    fn bundle_holds_every_path() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let mut tree = Tree::new(&hash_func, 3, &mut db).unwrap();
+        for i in &[3u64, 64, 300, 301] {
+            tree.update(&El::from(*i), El::one(), &mut db).unwrap();
+        }
+
+        let indices = [511u64, 0, 1, 9, 300, 301, 9];
+        let bundle = WitnessBundle::new(&db, &tree.root, 3, &indices).unwrap();
+        assert_eq!(bundle.indices, vec![0, 1, 9, 300, 301, 511]);
+        for i in &bundle.indices {
+            let path = bundle.auth_path(*i).unwrap();
+            assert_eq!(path, AuthPath::new(&tree, &db, *i).unwrap());
+            assert!(path.verify(&hash_func, &tree.root).unwrap());
+        }
+        assert!(bundle.auth_path(2).is_err());
+        // Fewer nodes than paths * depth, thanks to shared upper levels.
+        assert!(bundle.node_count() < bundle.indices.len() * 3);
+
+        let bytes = bundle.to_bytes();
+        let decoded = WitnessBundle::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, bundle);
+        assert!(WitnessBundle::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut issued = crate::bitmap::Bitmap::new(512).unwrap();
+        for i in &bundle.indices {
+            issued.set_bit(*i as usize);
+        }
+        let from_bits = WitnessBundle::for_bits(&db, &tree.root, 3, &issued).unwrap();
+        assert_eq!(from_bits, bundle);
+        assert!(WitnessBundle::new(&db, &tree.root, 3, &[512]).is_err());
+    }
+
+    #[test]
+    fn bundle_rejects_bad_bytes() {
+        let hash_params = make_hash_params();
+        let hash_func = make_hash_func(&hash_params);
+        let mut db = make_db();
+        let tree = Tree::new(&hash_func, 2, &mut db).unwrap();
+        let bundle = WitnessBundle::new(&db, &tree.root, 2, &[1, 5]).unwrap();
+        let bytes = bundle.to_bytes();
+        let index_count_at = 2 + EL_BYTES;
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = BUNDLE_FORMAT_VERSION + 1;
+        assert!(matches!(WitnessBundle::from_bytes(&wrong_version), Err(MerkleError::Encoding(_))));
+
+        // A huge count in a short input fails before allocating.
+        let mut huge = bytes[..index_count_at].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(WitnessBundle::from_bytes(&huge), Err(MerkleError::Encoding(_))));
+
+        let first_index = index_count_at + 4;
+        let mut unsorted = bytes.clone();
+        unsorted[first_index..first_index + 8].copy_from_slice(&9u64.to_be_bytes());
+        assert!(matches!(WitnessBundle::from_bytes(&unsorted), Err(MerkleError::Witness(_))));
+        let mut duplicate = bytes.clone();
+        duplicate[first_index..first_index + 8].copy_from_slice(&5u64.to_be_bytes());
+        assert!(WitnessBundle::from_bytes(&duplicate).is_err());
+        let mut out_of_range = bytes;
+        out_of_range[first_index + 8..first_index + 16].copy_from_slice(&64u64.to_be_bytes());
+        assert!(matches!(WitnessBundle::from_bytes(&out_of_range), Err(MerkleError::Witness(_))));
+    }
+}
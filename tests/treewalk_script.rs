@@ -0,0 +1,39 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn run_script(name: &str, script: &str) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!("treewalk-{}-{}.txt", name, std::process::id()));
+    fs::write(&path, script).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_treewalk"))
+        .args(["--depth", "2", "--script"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).ok();
+    output
+}
+
+#[test]
+fn script_session() {
+    let output = run_script("ok", "set 9\nleaf 9\nverify\nclear 9\nverify 9\nup\nq\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    // Commands are echoed with the prompt they ran at.
+    assert!(stdout.contains("\nroot> set 9\n"));
+    assert!(stdout.contains("\nroot/1> verify\n"));
+    assert!(stdout.contains("covers leaves 8..=15; 1 set"));
+    assert_eq!(stdout.matches("Path verifies.").count(), 2);
+    assert!(stdout.contains("covers leaves 8..=15; 0 set"));
+    assert!(stdout.contains("\nroot/1> up\n"));
+}
+
+#[test]
+fn script_stops_at_first_error() {
+    let output = run_script("err", "set 3\nset 64\nset 4\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success());
+    assert!(stderr.contains("Line 2: Leaf 64 is beyond a tree of depth 2 (capacity 64)."));
+    assert!(!stdout.contains("set 4"));
+}